@@ -0,0 +1,268 @@
+// Routing throughput of the real load balancer (`WorkerLoadBalancer::get_worker`) while the component map is being
+// republished as fast as possible, first by pushed events (each one clones and swaps in the whole map), then by
+// status poll refreshes asked for from several places at once (which should coalesce into far fewer polls)
+//
+// Run with `cargo bench --bench component_map`, optionally passing the number of reader threads (default 4)
+
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{self, Loop};
+use hyper::rt::Future;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Request, Response, Server};
+use reqwest::r#async::Client;
+use tokio::runtime::Runtime;
+
+use v9_router::balancing::Affinity;
+use v9_router::config::RouterConfig;
+use v9_router::load_balancer::WorkerLoadBalancer;
+use v9_router::model::{ComponentEvent, ComponentEventAction, ComponentId, ComponentPath};
+
+// Roughly a big deployment: lots of components, each on a handful of workers
+const COMPONENTS: usize = 2_000;
+const WORKERS: usize = 8;
+const WORKERS_PER_COMPONENT: usize = 4;
+// Places asking for a refresh at the same time (the timer, stale data, the admin API...)
+const REFRESHERS: usize = 8;
+const RUN_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy)]
+enum Churn {
+    None,
+    Pushes,
+    Refreshes,
+}
+
+impl Churn {
+    fn name(self) -> &'static str {
+        match self {
+            Churn::None => "no churn",
+            Churn::Pushes => "pushes",
+            Churn::Refreshes => "refreshes",
+        }
+    }
+}
+
+fn component_path(component: usize) -> ComponentPath {
+    ComponentPath {
+        user: format!("user{}", component % 97),
+        repo: format!("repo{}", component),
+    }
+}
+
+// Every component is on `WORKERS_PER_COMPONENT` workers in a row
+fn runs_on(component: usize, worker: usize) -> bool {
+    (worker + WORKERS - component % WORKERS) % WORKERS < WORKERS_PER_COMPONENT
+}
+
+fn status_response(worker: usize) -> String {
+    let components: Vec<String> = (0..COMPONENTS)
+        .filter(|&component| runs_on(component, worker))
+        .map(|component| {
+            let path = component_path(component);
+            format!(
+                r#"{{"id": {{"user": "{}", "repo": "{}", "hash": "h1"}}, "stat_window_seconds": 60.0, "hits": 0.0,
+                "avg_response_bytes": 0.0, "avg_ms_latency": 0.0, "ms_latency_percentiles": []}}"#,
+                path.user, path.repo
+            )
+        })
+        .collect();
+
+    format!(
+        r#"{{"cpu_usage": 0.1, "memory_usage": 0.1, "network_usage": 0.0, "active_components": [{}]}}"#,
+        components.join(", ")
+    )
+}
+
+// Runs the mock workers, returning their URLs, each answers status polls (counted in `polls`) with its components
+fn start_workers(polls: &Arc<AtomicU64>) -> Vec<String> {
+    let (url_sender, url_receiver) = mpsc::channel();
+    let polls = polls.clone();
+
+    thread::spawn(move || {
+        let servers: Vec<_> = (0..WORKERS)
+            .map(|worker| {
+                let status = status_response(worker);
+                let polls = polls.clone();
+                let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+                    let (status, polls) = (status.clone(), polls.clone());
+                    service_fn_ok(move |_: Request<Body>| {
+                        polls.fetch_add(1, Ordering::Relaxed);
+                        Response::new(Body::from(status.clone()))
+                    })
+                });
+                url_sender
+                    .send(format!("http://127.0.0.1:{}", server.local_addr().port()))
+                    .unwrap();
+                server.map_err(|e| panic!("Mock worker failed: {}", e))
+            })
+            .collect();
+        hyper::rt::run(future::join_all(servers).map(|_| ()));
+    });
+
+    url_receiver.iter().take(WORKERS).collect()
+}
+
+// Takes one replica out and puts it back, then the next one, as fast as pushes can be applied
+fn push_churn(load_balancer: &WorkerLoadBalancer, worker_urls: &[String], stop: &AtomicBool) -> u64 {
+    let mut pushes = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let component = (pushes / 2) as usize % COMPONENTS;
+        let worker = (0..WORKERS).find(|&worker| runs_on(component, worker)).unwrap();
+        let action = if pushes % 2 == 0 {
+            ComponentEventAction::Remove
+        } else {
+            ComponentEventAction::Add
+        };
+        let event = ComponentEvent {
+            action,
+            component: ComponentId {
+                path: component_path(component),
+                hash: "h1".to_string(),
+            },
+            worker: worker_urls[worker].clone(),
+            generation: None,
+        };
+
+        load_balancer.apply_component_events(&[event]).unwrap();
+        pushes += 1;
+    }
+    pushes
+}
+
+// Each refresher asks for a refresh again as soon as the last one it asked for is done
+fn refresh_churn(
+    load_balancer: &Arc<WorkerLoadBalancer>,
+    runtime: &mut Runtime,
+    stop: &Arc<AtomicBool>,
+) -> u64 {
+    let refreshes = Arc::new(AtomicU64::new(0));
+    let refreshers: Vec<_> = (0..REFRESHERS)
+        .map(|_| {
+            let (load_balancer, stop, refreshes) =
+                (load_balancer.clone(), stop.clone(), refreshes.clone());
+            future::loop_fn((), move |()| {
+                let (stop, refreshes) = (stop.clone(), refreshes.clone());
+                load_balancer.clone().update_component_map().map(move |()| {
+                    refreshes.fetch_add(1, Ordering::Relaxed);
+                    if stop.load(Ordering::Relaxed) {
+                        Loop::Break(())
+                    } else {
+                        Loop::Continue(())
+                    }
+                })
+            })
+        })
+        .collect();
+
+    runtime.block_on(future::join_all(refreshers)).unwrap();
+    refreshes.load(Ordering::Relaxed)
+}
+
+// Returns (reads per second, map updates asked for per second)
+fn run(
+    load_balancer: &Arc<WorkerLoadBalancer>,
+    worker_urls: &[String],
+    runtime: &mut Runtime,
+    readers: usize,
+    churn: Churn,
+) -> (f64, f64) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+
+    let reader_threads: Vec<_> = (0..readers)
+        .map(|reader| {
+            let (load_balancer, stop, reads) = (load_balancer.clone(), stop.clone(), reads.clone());
+            thread::spawn(move || {
+                let affinity = Affinity::Client(format!("10.0.0.{}", reader));
+                let mut local_reads = 0_u64;
+                let mut component = reader;
+                while !stop.load(Ordering::Relaxed) {
+                    load_balancer
+                        .get_worker(&component_path(component % COMPONENTS), None, &affinity)
+                        .unwrap();
+                    component += 7;
+                    local_reads += 1;
+                }
+                reads.fetch_add(local_reads, Ordering::Relaxed);
+            })
+        })
+        .collect();
+
+    let stopper = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            thread::sleep(RUN_TIME);
+            stop.store(true, Ordering::Relaxed);
+        })
+    };
+    let updates = match churn {
+        Churn::None => {
+            thread::sleep(RUN_TIME);
+            0
+        }
+        Churn::Pushes => push_churn(load_balancer, worker_urls, &stop),
+        Churn::Refreshes => refresh_churn(load_balancer, runtime, &stop),
+    };
+    stopper.join().unwrap();
+    for reader in reader_threads {
+        reader.join().unwrap();
+    }
+
+    let secs = RUN_TIME.as_secs_f64();
+    (reads.load(Ordering::Relaxed) as f64 / secs, updates as f64 / secs)
+}
+
+fn main() {
+    let readers = env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(4);
+
+    let polls = Arc::new(AtomicU64::new(0));
+    let worker_urls = start_workers(&polls);
+    let config = RouterConfig {
+        workers: worker_urls.clone(),
+        ..RouterConfig::default()
+    };
+    let load_balancer = WorkerLoadBalancer::new(Client::new(), &config);
+    let mut runtime = Runtime::new().unwrap();
+    runtime
+        .block_on(load_balancer.clone().update_component_map())
+        .unwrap();
+    assert!(
+        load_balancer.has_component(&component_path(0)),
+        "The first refresh found nothing"
+    );
+
+    println!(
+        "{} components on {} of {} workers each, {} reader threads, {}s per run",
+        COMPONENTS,
+        WORKERS_PER_COMPONENT,
+        WORKERS,
+        readers,
+        RUN_TIME.as_secs()
+    );
+
+    for churn in &[Churn::None, Churn::Pushes, Churn::Refreshes] {
+        let polls_before = polls.load(Ordering::Relaxed);
+        let (reads_per_sec, updates_per_sec) =
+            run(&load_balancer, &worker_urls, &mut runtime, readers, *churn);
+        print!("{:>10}: {:>12.0} get_worker/s", churn.name(), reads_per_sec);
+        match churn {
+            Churn::None => println!(),
+            Churn::Pushes => println!(" ({:.0} pushes/s)", updates_per_sec),
+            // Coalescing means many refreshes asked for share one round of polls
+            Churn::Refreshes => {
+                let poll_rounds = (polls.load(Ordering::Relaxed) - polls_before) as f64 / WORKERS as f64;
+                println!(
+                    " ({:.0} refreshes asked for/s, {:.0} rounds of polls/s)",
+                    updates_per_sec,
+                    poll_rounds / RUN_TIME.as_secs_f64()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either, Loop, Shared, SharedError};
+use hyper::rt::Future;
+use parking_lot::{Mutex, RwLock};
+use tokio::timer::Delay;
+use tokio::util::FutureExt;
+
+use crate::config::{self, ActivationConfig};
+use crate::error::RouterError;
+use crate::load_balancer::WorkerLoadBalancer;
+use crate::model::{ActivateRequest, ActivationStatus, ComponentId, ComponentPath, ExecutionMethod};
+use crate::worker::WorkerNode;
+
+// How often a refresh is asked for while waiting for a freshly activated component to make it into the component map
+// (the refresh rate limit decides whether one actually happens)
+const ACTIVATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+type SharedActivation = Shared<Box<dyn Future<Item = (), Error = RouterError> + Send>>;
+
+// An activation, and when it started so one that's outlived the cold start timeout can be told apart from a running one
+struct PendingActivation {
+    started: Instant,
+    activation: SharedActivation,
+}
+
+// What to run for each component, as read from the registry file:
+//
+// [components."alice/thumbnailer"]
+// hash = "3f2a9c"
+// executable_file = "/srv/v9/alice-thumbnailer.tar"
+// execution_method = "docker-archive"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    components: BTreeMap<String, RegistryEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryEntry {
+    hash: String,
+    executable_file: String,
+    execution_method: ExecutionMethod,
+}
+
+#[derive(Debug, Default)]
+pub struct ComponentRegistry {
+    components: HashMap<ComponentPath, RegistryEntry>,
+}
+
+impl ComponentRegistry {
+    pub fn load(path: &Path) -> Result<Self, RouterError> {
+        let file_contents =
+            fs::read_to_string(path).map_err(|e| RouterError::ConfigFile(path.to_path_buf(), e))?;
+        let registry_file: RegistryFile = toml::from_str(&file_contents)?;
+
+        let mut components = HashMap::new();
+        for (component, entry) in registry_file.components {
+            let component_path = config::parse_component_path(&component).ok_or_else(|| {
+                RouterError::InvalidConfig(format!(
+                    "component registry keys must look like \"user/repo\", got {:?}",
+                    component
+                ))
+            })?;
+            components.insert(component_path, entry);
+        }
+
+        Ok(Self { components })
+    }
+
+    fn activate_request(&self, path: &ComponentPath) -> Option<ActivateRequest> {
+        let entry = self.components.get(path)?;
+        Some(ActivateRequest {
+            id: ComponentId {
+                path: path.clone(),
+                hash: entry.hash.clone(),
+            },
+            executable_file: entry.executable_file.clone(),
+            execution_method: entry.execution_method.clone(),
+        })
+    }
+}
+
+// Starts components that aren't running anywhere, so requests for them can be held instead of getting a 404
+pub struct Activator {
+    load_balancer: Arc<WorkerLoadBalancer>,
+    registry_file: PathBuf,
+    registry: RwLock<ComponentRegistry>,
+    cold_start_timeout: Duration,
+    // Only one activation per component at a time, requests that arrive while one is going just wait on it
+    pending: Mutex<HashMap<ComponentPath, PendingActivation>>,
+}
+
+// Note: The pending activations are futures, so we can only show which components they're for
+impl Debug for Activator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Activator")
+            .field("registry_file", &self.registry_file)
+            .field("registry", &self.registry)
+            .field("cold_start_timeout", &self.cold_start_timeout)
+            .field("pending", &self.pending.lock().keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Activator {
+    // Returns `None` if activation is turned off
+    pub fn new(
+        load_balancer: Arc<WorkerLoadBalancer>,
+        config: &ActivationConfig,
+    ) -> Result<Option<Self>, RouterError> {
+        config
+            .registry_file
+            .as_ref()
+            .map(|registry_file| {
+                let registry = ComponentRegistry::load(registry_file)?;
+                info!(
+                    "Loaded {} components from registry {}",
+                    registry.components.len(),
+                    registry_file.display()
+                );
+
+                Ok(Self {
+                    load_balancer,
+                    registry_file: registry_file.clone(),
+                    registry: RwLock::new(registry),
+                    cold_start_timeout: config.cold_start_timeout(),
+                    pending: Mutex::new(HashMap::new()),
+                })
+            })
+            .transpose()
+    }
+
+    // Note: The registry is always reloaded from the file it was first loaded from
+    pub fn reload_registry(&self) -> Result<(), RouterError> {
+        let registry = ComponentRegistry::load(&self.registry_file)?;
+        info!(
+            "Reloaded {} components from registry {}",
+            registry.components.len(),
+            self.registry_file.display()
+        );
+        *self.registry.write() = registry;
+
+        Ok(())
+    }
+
+    // A request pinned to a version can only activate the version the registry has
+    pub fn can_activate(&self, path: &ComponentPath, version: Option<&str>) -> bool {
+        self.registry
+            .read()
+            .components
+            .get(path)
+            .is_some_and(|entry| version.is_none_or(|version| version == entry.hash))
+    }
+
+    // Resolves once the component is in the component map, or fails after `cold_start_timeout`
+    pub fn activate(
+        self: &Arc<Self>,
+        path: &ComponentPath,
+    ) -> impl Future<Item = (), Error = RouterError> + Send {
+        let mut pending = self.pending.lock();
+        // Note: An activation only makes progress while someone waits on it, so if every request waiting on it went
+        // away it sits here with its timeout running out, and is replaced rather than handed to the next request
+        if pending
+            .get(path)
+            .is_some_and(|pending| pending.started.elapsed() >= self.cold_start_timeout)
+        {
+            debug!("Replacing an abandoned activation of {}", path);
+            pending.remove(path);
+        }
+        let activation = pending
+            .entry(path.clone())
+            .or_insert_with(|| {
+                let started = Instant::now();
+                PendingActivation {
+                    started,
+                    activation: self.clone().start_activation(path.clone(), started).shared(),
+                }
+            })
+            .activation
+            .clone();
+
+        activation.map(|_| ()).map_err(|e| shared_activation_error(&e))
+    }
+
+    fn start_activation(
+        self: Arc<Self>,
+        path: ComponentPath,
+        activation_started: Instant,
+    ) -> Box<dyn Future<Item = (), Error = RouterError> + Send> {
+        Box::new(self.activation(&path).then(move |result| {
+            // An abandoned activation that finishes late mustn't remove the one that replaced it
+            let mut pending = self.pending.lock();
+            if pending
+                .get(&path)
+                .is_some_and(|pending| pending.started == activation_started)
+            {
+                pending.remove(&path);
+            }
+            drop(pending);
+            match &result {
+                Ok(()) => info!(
+                    "Activated {} in {:.2}s",
+                    path,
+                    activation_started.elapsed().as_secs_f64()
+                ),
+                Err(e) => warn!("Activating {} failed: {}", path, e),
+            }
+
+            result
+        }))
+    }
+
+    fn activation(
+        &self,
+        path: &ComponentPath,
+    ) -> Box<dyn Future<Item = (), Error = RouterError> + Send> {
+        let request = self.registry.read().activate_request(path);
+        let (request, worker) = match (request, self.choose_worker()) {
+            (Some(request), Some(worker)) => (request, worker),
+            (None, _) => {
+                return Box::new(future::err(RouterError::ActivationFailed(format!(
+                    "{} is not in the component registry",
+                    path
+                ))))
+            }
+            (_, None) => return Box::new(future::err(RouterError::NoAvailableWorker(path.to_string()))),
+        };
+        info!(
+            "Activating {}@{} on {}...",
+            path,
+            request.id.hash,
+            worker.request_url()
+        );
+
+        let worker_url = worker.request_url().to_string();
+        let load_balancer = self.load_balancer.clone();
+        let waiting_path = path.clone();
+        let timed_out_path = path.to_string();
+
+        let activation = worker
+            .activate(&request)
+            .and_then(move |response| match response.result {
+                ActivationStatus::ActivationSuccessful | ActivationStatus::AlreadyRunning => Ok(()),
+                result => Err(RouterError::ActivationFailed(format!(
+                    "worker {} answered {:?}: {}",
+                    worker_url, result, response.dbg_message
+                ))),
+            })
+            .and_then(move |()| wait_for_component(load_balancer, waiting_path))
+            .timeout(self.cold_start_timeout)
+            .map_err(move |e| {
+                if e.is_elapsed() {
+                    RouterError::ColdStartTimeout(timed_out_path)
+                } else {
+                    RouterError::from(e)
+                }
+            });
+
+        Box::new(activation)
+    }
+
+    // The available worker with the most headroom (workers that haven't reported any load yet count as idle)
+    fn choose_worker(&self) -> Option<Arc<WorkerNode>> {
+        self.load_balancer
+            .workers()
+            .into_iter()
+            .filter(|worker| worker.is_available())
+            .min_by(|a, b| {
+                let busiest = |worker: &WorkerNode| worker.load().map_or(0.0, |load| load.busiest());
+                busiest(a).total_cmp(&busiest(b))
+            })
+    }
+}
+
+// The worker has to report the component in a status poll (or the component has to be pushed) before we can route to
+// it, so we wait for a map with it in to be published, asking for a refresh now and then in case nothing else does
+fn wait_for_component(
+    load_balancer: Arc<WorkerLoadBalancer>,
+    path: ComponentPath,
+) -> impl Future<Item = (), Error = RouterError> + Send {
+    future::loop_fn((), move |()| {
+        if load_balancer.has_component(&path) {
+            return Either::A(future::ok(Loop::Break(())));
+        }
+
+        let published = load_balancer.wait_for_publish(&path);
+        load_balancer.clone().request_refresh();
+        let poll_again = Delay::new(Instant::now() + ACTIVATION_POLL_INTERVAL).map_err(|_| ());
+        Either::B(published.select2(poll_again).then(|_| Ok(Loop::Continue(()))))
+    })
+}
+
+// Everyone waiting on an activation gets a copy of how it failed
+fn shared_activation_error(e: &SharedError<RouterError>) -> RouterError {
+    match &**e {
+        RouterError::ColdStartTimeout(p) => RouterError::ColdStartTimeout(p.clone()),
+        RouterError::NoAvailableWorker(p) => RouterError::NoAvailableWorker(p.clone()),
+        RouterError::ActivationFailed(msg) => RouterError::ActivationFailed(msg.clone()),
+        e => RouterError::ActivationFailed(e.to_string()),
+    }
+}
@@ -0,0 +1,311 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Either};
+use hyper::header::CONTENT_TYPE;
+use hyper::rt::{self, Future, Stream};
+use hyper::{Body, Method, Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio_signal::unix::{Signal, SIGHUP};
+
+use crate::activation::Activator;
+use crate::body::LimitedBody;
+use crate::config::{self, RouterConfig, TrafficSplit};
+use crate::error::RouterError;
+use crate::load_balancer::WorkerLoadBalancer;
+use crate::metrics;
+use crate::model::ComponentPath;
+use crate::worker::{ComponentLatency, Scheduling, WorkerStatus};
+
+// Admin requests are tiny JSON documents, anything bigger than this is a mistake
+const MAX_ADMIN_BODY_BYTES: u64 = 64 * 1024;
+
+pub fn global_admin_entrypoint(
+    handler: Arc<AdminHandler>,
+    remote_addr: SocketAddr,
+    req: Request<Body>,
+) -> impl Future<Item = Response<Body>, Error = hyper::error::Error> + Send {
+    debug!("Admin request from {}: {:?}", remote_addr, req);
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+
+    LimitedBody::new(req.into_body(), MAX_ADMIN_BODY_BYTES)
+        .concat2()
+        .map_err(RouterError::BodyStream)
+        .and_then(move |body| handler.handle(&method, &path, &body))
+        .then(|resp_result| {
+            let resp: Response<Body> = resp_result.unwrap_or_else(|e| {
+                warn!("Admin request failed: {}", e);
+                e.into()
+            });
+            debug!("{:?}", resp);
+
+            Ok(resp)
+        })
+}
+
+// Reload on SIGHUP, the same as a `POST /admin/reload` (this runs even if the admin listener is disabled)
+pub fn reload_on_sighup(handler: Arc<AdminHandler>) -> impl Future<Item = (), Error = ()> + Send {
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map_err(|e| error!("Could not listen for SIGHUP: {}", e))
+        .for_each(move |_| {
+            info!("Received SIGHUP, reloading config...");
+            if let Err(e) = handler.reload_config() {
+                error!("Config reload failed: {}", e);
+            }
+            Ok(())
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkerRequest {
+    worker: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentStatus {
+    component: String,
+    // The version requests that don't pin one currently go to (when there's no traffic split)
+    preferred_version: Option<String>,
+    traffic_split: Option<TrafficSplit>,
+    // Newest first
+    versions: Vec<VersionStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct VersionStatus {
+    hash: String,
+    workers: Vec<ReplicaStatus>,
+}
+
+// A worker, plus how it's doing with one particular component
+#[derive(Debug, Serialize)]
+struct ReplicaStatus {
+    #[serde(flatten)]
+    worker: WorkerStatus,
+    latency: ComponentLatency,
+    component_in_flight_requests: usize,
+}
+
+#[derive(Debug)]
+pub struct AdminHandler {
+    load_balancer: Arc<WorkerLoadBalancer>,
+    activator: Option<Arc<Activator>>,
+}
+
+impl AdminHandler {
+    pub fn new(load_balancer: Arc<WorkerLoadBalancer>, activator: Option<Arc<Activator>>) -> Self {
+        Self {
+            load_balancer,
+            activator,
+        }
+    }
+
+    fn handle(
+        &self,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+    ) -> impl Future<Item = Response<Body>, Error = RouterError> + Send {
+        // Forcing a refresh is the only thing that has to wait on anything, the rest is answered right away
+        if (method, path) == (&Method::POST, "/admin/refresh") {
+            let load_balancer = self.load_balancer.clone();
+            return Either::A(
+                load_balancer
+                    .clone()
+                    .update_component_map()
+                    .then(move |_| json_response(&load_balancer.component_snapshot())),
+            );
+        }
+
+        Either::B(future::result(self.handle_immediately(method, path, body)))
+    }
+
+    fn handle_immediately(
+        &self,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Response<Body>, RouterError> {
+        // Note: All paths start with a slash, so we skip the first entry in the split (which is always just "")
+        let path_components: Vec<&str> = path.split('/').skip(1).collect();
+
+        match (method, path_components.as_slice()) {
+            (&Method::GET, ["metrics"]) => Ok(Response::builder()
+                .header(CONTENT_TYPE, metrics::content_type())
+                .body(Body::from(metrics::encode()?))
+                .unwrap()),
+
+            (&Method::GET, ["admin", "workers"]) => json_response(&self.worker_statuses()),
+
+            (&Method::PUT, ["admin", "workers"]) => {
+                let worker_urls: Vec<String> = parse_json_body(body, "a JSON list of worker urls")?;
+                self.set_workers(&worker_urls)?;
+                json_response(&self.worker_statuses())
+            }
+
+            (&Method::POST, ["admin", "workers", action]) => {
+                let scheduling = match *action {
+                    "schedulable" => Scheduling::Schedulable,
+                    "unschedulable" => Scheduling::Unschedulable,
+                    "drain" => Scheduling::Draining,
+                    _ => return Err(RouterError::PathNotFound(path.to_string())),
+                };
+
+                let request: WorkerRequest = parse_json_body(body, r#"{"worker": "<worker url>"}"#)?;
+                let worker = self.load_balancer.find_worker(&request.worker).ok_or_else(|| {
+                    RouterError::PathNotFound(format!("no such worker: {}", request.worker))
+                })?;
+                worker.set_scheduling(scheduling);
+
+                json_response(&worker.status())
+            }
+
+            (&Method::GET, ["admin", "components"]) => {
+                json_response(&self.load_balancer.component_snapshot())
+            }
+
+            (&Method::GET, ["admin", "components", user, repo]) => {
+                let component_path = ComponentPath {
+                    user: user.to_string(),
+                    repo: repo.to_string(),
+                };
+                let versions = self
+                    .load_balancer
+                    .component_versions(&component_path)
+                    .ok_or_else(|| {
+                        RouterError::PathNotFound(format!("no such component: {}", component_path))
+                    })?;
+
+                json_response(&ComponentStatus {
+                    component: component_path.to_string(),
+                    preferred_version: self.load_balancer.preferred_version(&component_path),
+                    traffic_split: self.load_balancer.traffic_split(&component_path),
+                    versions: versions
+                        .into_iter()
+                        .map(|version| VersionStatus {
+                            hash: version.hash,
+                            workers: version
+                                .workers
+                                .iter()
+                                .map(|worker| ReplicaStatus {
+                                    worker: worker.status(),
+                                    latency: worker.component_latency(&component_path),
+                                    component_in_flight_requests: worker
+                                        .component_in_flight_requests(&component_path),
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                })
+            }
+
+            (&Method::GET, ["admin", "circuit-breakers"]) => {
+                let circuit_breakers: Vec<_> = self
+                    .load_balancer
+                    .workers()
+                    .iter()
+                    .map(|worker| worker.circuit_breakers())
+                    .collect();
+                json_response(&circuit_breakers)
+            }
+
+            (&Method::GET, ["admin", "splits"]) => json_response(&self.load_balancer.traffic_splits()),
+
+            (&Method::PUT, ["admin", "splits", user, repo]) => {
+                let split: TrafficSplit =
+                    parse_json_body(body, r#"{"<version hash>": <percentage>, ...}"#)?;
+                config::validate_traffic_split(&split)
+                    .map_err(|e| RouterError::InvalidAdminRequest(format!("traffic split {}", e)))?;
+
+                self.load_balancer
+                    .set_traffic_split(ComponentPath::new(user.to_string(), repo.to_string()), split);
+                json_response(&self.load_balancer.traffic_splits())
+            }
+
+            (&Method::DELETE, ["admin", "splits", user, repo]) => {
+                let component_path = ComponentPath::new(user.to_string(), repo.to_string());
+                if !self.load_balancer.remove_traffic_split(&component_path) {
+                    return Err(RouterError::PathNotFound(format!(
+                        "no traffic split for {}",
+                        component_path
+                    )));
+                }
+                json_response(&self.load_balancer.traffic_splits())
+            }
+
+            (&Method::POST, ["admin", "reload"]) => {
+                self.reload_config()?;
+                json_response(&self.worker_statuses())
+            }
+
+            _ => Err(RouterError::PathNotFound(path.to_string())),
+        }
+    }
+
+    fn worker_statuses(&self) -> Vec<WorkerStatus> {
+        self.load_balancer
+            .workers()
+            .iter()
+            .map(|worker| worker.status())
+            .collect()
+    }
+
+    // Only the worker list (and the component registry) is hot reloaded, everything else in the config needs a
+    // restart to change
+    // Note: A list set through `PUT /admin/workers` is replaced by whatever the config says here
+    fn reload_config(&self) -> Result<(), RouterError> {
+        let config = RouterConfig::load()?;
+        if RouterConfig::workers_from_command_line() {
+            warn!(
+                "Workers are set with --workers / V9_WORKERS, so edits to the config file's workers are ignored on reload"
+            );
+        }
+        // Note: A broken registry file doesn't stop the worker list from being reloaded, or the other way round
+        let registry_result = self
+            .activator
+            .as_ref()
+            .map_or(Ok(()), |activator| activator.reload_registry());
+        let workers_result = self.set_workers(&config.workers);
+
+        match (registry_result, workers_result) {
+            (Ok(()), result) | (result, Ok(())) => result,
+            (Err(registry_error), Err(workers_error)) => Err(RouterError::InvalidConfig(format!(
+                "reloading the component registry failed ({}), and so did reloading the workers ({})",
+                registry_error, workers_error
+            ))),
+        }
+    }
+
+    fn set_workers(&self, worker_urls: &[String]) -> Result<(), RouterError> {
+        if worker_urls.is_empty() {
+            return Err(RouterError::InvalidAdminRequest(
+                "refusing to remove every worker".to_string(),
+            ));
+        }
+        config::validate_worker_urls(worker_urls).map_err(RouterError::InvalidAdminRequest)?;
+
+        self.load_balancer.set_workers(worker_urls);
+        info!("Now routing to workers {:?}", self.load_balancer.worker_urls());
+
+        // Pick up the new set's components right away, instead of waiting for the next scheduled refresh
+        rt::spawn(self.load_balancer.clone().update_component_map());
+
+        Ok(())
+    }
+}
+
+fn parse_json_body<T: DeserializeOwned>(body: &[u8], expected: &str) -> Result<T, RouterError> {
+    serde_json::from_slice(body)
+        .map_err(|e| RouterError::InvalidAdminRequest(format!("expected {}: {}", expected, e)))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>, RouterError> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value)?))
+        .unwrap())
+}
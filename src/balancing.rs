@@ -0,0 +1,374 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use rand::Rng;
+
+use crate::config::{BalancingMode, LoadBalancerConfig};
+use crate::worker::{ComponentLatency, WorkerLoad};
+
+// Even a saturated worker keeps a trickle of traffic under `Weighted`, so we notice when it frees up
+const MIN_LOAD_WEIGHT: f64 = 0.05;
+
+// Everything a strategy gets to know about one worker it could send a request to
+// (these are plain values, so strategies can be exercised without any real workers)
+#[derive(Clone, Debug)]
+pub struct Candidate<'a> {
+    pub worker_url: &'a str,
+    pub load: Option<WorkerLoad>,
+    pub latency: ComponentLatency,
+    // Requests for this component we're waiting on the worker for
+    pub in_flight: usize,
+}
+
+// What a request can be kept on the same worker by
+#[derive(Clone, Debug)]
+pub enum Affinity {
+    // Found where the component's sticky config said to look, so the request always sticks
+    Sticky(String),
+    // Just the client's address, this only matters under `consistent_hash` balancing
+    Client(String),
+}
+
+impl Affinity {
+    pub fn key(&self) -> &str {
+        match self {
+            Affinity::Sticky(key) | Affinity::Client(key) => key,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SelectionContext<'a> {
+    // Goes up by one for every selection made for this component
+    pub sequence: usize,
+    // Requests with the same key should land on the same worker (for strategies that care)
+    pub affinity_key: Option<&'a str>,
+}
+
+// Picks one of `candidates` (by index), every candidate passed in is healthy and schedulable
+pub trait BalancingStrategy: Debug + Send + Sync {
+    fn choose(&self, candidates: &[Candidate<'_>], context: &SelectionContext<'_>) -> Option<usize>;
+}
+
+pub fn strategy_for(mode: BalancingMode, config: &LoadBalancerConfig) -> Box<dyn BalancingStrategy> {
+    match mode {
+        BalancingMode::RoundRobin => Box::new(RoundRobin),
+        BalancingMode::Random => Box::new(Random),
+        BalancingMode::LoadAware => Box::new(Weighted),
+        BalancingMode::LeastLatency => Box::new(LeastLatency {
+            observed_latency_weight: config.observed_latency_weight,
+        }),
+        BalancingMode::LeastOutstanding => Box::new(LeastOutstanding),
+        BalancingMode::ConsistentHash => Box::new(ConsistentHash),
+    }
+}
+
+#[derive(Debug)]
+pub struct RoundRobin;
+
+impl BalancingStrategy for RoundRobin {
+    fn choose(&self, candidates: &[Candidate<'_>], context: &SelectionContext<'_>) -> Option<usize> {
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(context.sequence % candidates.len())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Random;
+
+impl BalancingStrategy for Random {
+    fn choose(&self, candidates: &[Candidate<'_>], _context: &SelectionContext<'_>) -> Option<usize> {
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(rand::thread_rng().gen_range(0, candidates.len()))
+        }
+    }
+}
+
+// A weighted random pick, where each worker's weight is how much headroom it reported on its busiest resource
+// (workers that haven't reported any load yet are treated as idle)
+#[derive(Debug)]
+pub struct Weighted;
+
+impl BalancingStrategy for Weighted {
+    fn choose(&self, candidates: &[Candidate<'_>], _context: &SelectionContext<'_>) -> Option<usize> {
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|candidate| {
+                let headroom = candidate.load.map_or(1.0, |load| 1.0 - load.busiest());
+                headroom.max(MIN_LOAD_WEIGHT)
+            })
+            .collect();
+
+        let total_weight: f64 = weights.iter().sum();
+        let mut target = rand::thread_rng().gen_range(0.0, total_weight.max(MIN_LOAD_WEIGHT));
+        for (idx, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return Some(idx);
+            }
+            target -= weight;
+        }
+
+        // Floating point rounding can leave us just past the end
+        candidates.len().checked_sub(1)
+    }
+}
+
+// Power of two choices: compare two random workers and take the faster one
+// (so one slow replica loses traffic without every request piling onto whichever is fastest right now)
+#[derive(Debug)]
+pub struct LeastLatency {
+    pub observed_latency_weight: f64,
+}
+
+impl BalancingStrategy for LeastLatency {
+    fn choose(&self, candidates: &[Candidate<'_>], _context: &SelectionContext<'_>) -> Option<usize> {
+        if candidates.len() < 2 {
+            return candidates.len().checked_sub(1);
+        }
+
+        let mut rng = rand::thread_rng();
+        let first_idx = rng.gen_range(0, candidates.len());
+        let mut second_idx = rng.gen_range(0, candidates.len() - 1);
+        if second_idx >= first_idx {
+            second_idx += 1;
+        }
+
+        // Replicas we know nothing about yet look instantly fast, so they get tried
+        let estimate_ms = |idx: usize| {
+            candidates[idx]
+                .latency
+                .estimate_ms(self.observed_latency_weight)
+                .unwrap_or(0.0)
+        };
+        if estimate_ms(second_idx) < estimate_ms(first_idx) {
+            Some(second_idx)
+        } else {
+            Some(first_idx)
+        }
+    }
+}
+
+// Whoever has the fewest requests for this component in flight from us, ties are broken round robin
+// (so a replica stuck on slow requests stops being handed more, which plain round robin would keep doing)
+#[derive(Debug)]
+pub struct LeastOutstanding;
+
+impl BalancingStrategy for LeastOutstanding {
+    fn choose(&self, candidates: &[Candidate<'_>], context: &SelectionContext<'_>) -> Option<usize> {
+        (0..candidates.len())
+            .map(|offset| (context.sequence + offset) % candidates.len())
+            .min_by_key(|idx| candidates[*idx].in_flight)
+    }
+}
+
+// Rendezvous hashing on the affinity key, so a key keeps its worker unless that worker goes away
+// (and when one does, only the keys that were on it move), without a key this is just round robin
+#[derive(Debug)]
+pub struct ConsistentHash;
+
+impl BalancingStrategy for ConsistentHash {
+    fn choose(&self, candidates: &[Candidate<'_>], context: &SelectionContext<'_>) -> Option<usize> {
+        match context.affinity_key {
+            Some(affinity_key) => (0..candidates.len()).max_by_key(|idx| {
+                let mut hasher = DefaultHasher::new();
+                (affinity_key, candidates[*idx].worker_url).hash(&mut hasher);
+                hasher.finish()
+            }),
+            None => RoundRobin.choose(candidates, context),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouterConfig;
+
+    const WORKER_URLS: [&str; 5] = [
+        "http://v9_w1.example.com",
+        "http://v9_w2.example.com",
+        "http://v9_w3.example.com",
+        "http://v9_w4.example.com",
+        "http://v9_w5.example.com",
+    ];
+
+    fn candidates(count: usize) -> Vec<Candidate<'static>> {
+        WORKER_URLS[..count]
+            .iter()
+            .map(|worker_url| Candidate {
+                worker_url,
+                load: None,
+                latency: ComponentLatency::default(),
+                in_flight: 0,
+            })
+            .collect()
+    }
+
+    fn context(sequence: usize, affinity_key: Option<&str>) -> SelectionContext<'_> {
+        SelectionContext {
+            sequence,
+            affinity_key,
+        }
+    }
+
+    fn busiest_load(busiest: f64) -> WorkerLoad {
+        WorkerLoad {
+            cpu: busiest,
+            memory: 0.0,
+            network: 0.0,
+        }
+    }
+
+    #[test]
+    fn every_strategy_needs_a_candidate() {
+        let config = RouterConfig::default().load_balancer;
+        for mode in &[
+            BalancingMode::RoundRobin,
+            BalancingMode::Random,
+            BalancingMode::LoadAware,
+            BalancingMode::LeastLatency,
+            BalancingMode::LeastOutstanding,
+            BalancingMode::ConsistentHash,
+        ] {
+            let strategy = strategy_for(*mode, &config);
+            assert_eq!(strategy.choose(&[], &context(0, Some("key"))), None, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns_in_order() {
+        let candidates = candidates(3);
+        let chosen: Vec<_> = (0..7)
+            .map(|sequence| RoundRobin.choose(&candidates, &context(sequence, None)).unwrap())
+            .collect();
+
+        assert_eq!(chosen, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn weighted_shares_traffic_by_headroom() {
+        let mut candidates = candidates(3);
+        candidates[0].load = Some(busiest_load(0.0));
+        candidates[1].load = Some(busiest_load(0.75));
+        // Saturated, but still gets a trickle
+        candidates[2].load = Some(busiest_load(1.0));
+
+        let mut chosen = [0_usize; 3];
+        let draws = 20_000;
+        for sequence in 0..draws {
+            chosen[Weighted.choose(&candidates, &context(sequence, None)).unwrap()] += 1;
+        }
+
+        // Weights are 1.0, 0.25 and 0.05
+        let share = |idx: usize| chosen[idx] as f64 / draws as f64;
+        assert!((share(0) - 1.0 / 1.3).abs() < 0.03, "{:?}", chosen);
+        assert!((share(1) - 0.25 / 1.3).abs() < 0.03, "{:?}", chosen);
+        assert!(chosen[2] > 0, "{:?}", chosen);
+    }
+
+    #[test]
+    fn least_latency_never_picks_the_slowest_of_its_two_choices() {
+        let mut candidates = candidates(3);
+        candidates[0].latency.reported_ms = Some(100.0);
+        candidates[1].latency.reported_ms = Some(10.0);
+        candidates[2].latency.reported_ms = Some(50.0);
+        let strategy = LeastLatency {
+            observed_latency_weight: 0.5,
+        };
+
+        let mut chosen = [0_usize; 3];
+        for sequence in 0..1000 {
+            chosen[strategy.choose(&candidates, &context(sequence, None)).unwrap()] += 1;
+        }
+
+        // The slowest loses every comparison it's in, and the fastest wins every one it's in (two thirds of them)
+        assert_eq!(chosen[0], 0, "{:?}", chosen);
+        assert!(chosen[1] > chosen[2], "{:?}", chosen);
+
+        let two = &candidates[..2];
+        assert_eq!(strategy.choose(two, &context(0, None)), Some(1));
+    }
+
+    #[test]
+    fn least_latency_tries_replicas_it_knows_nothing_about() {
+        let mut candidates = candidates(2);
+        candidates[0].latency.reported_ms = Some(10.0);
+        let strategy = LeastLatency {
+            observed_latency_weight: 0.5,
+        };
+
+        assert_eq!(strategy.choose(&candidates, &context(0, None)), Some(1));
+    }
+
+    #[test]
+    fn least_outstanding_picks_the_least_busy_breaking_ties_in_turn() {
+        let mut candidates = candidates(4);
+        candidates[0].in_flight = 3;
+        candidates[1].in_flight = 1;
+        candidates[2].in_flight = 5;
+        candidates[3].in_flight = 1;
+
+        let chosen: Vec<_> = (0..4)
+            .map(|sequence| {
+                LeastOutstanding
+                    .choose(&candidates, &context(sequence, None))
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(chosen, vec![1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_on_the_same_worker() {
+        let candidates = candidates(5);
+        for key in &["alice", "bob", "carol"] {
+            let first = ConsistentHash.choose(&candidates, &context(0, Some(key)));
+            for sequence in 1..10 {
+                assert_eq!(
+                    ConsistentHash.choose(&candidates, &context(sequence, Some(key))),
+                    first
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_the_removed_workers_keys() {
+        let all = candidates(5);
+        let removed_url = all[2].worker_url;
+        let remaining: Vec<_> = all
+            .iter()
+            .filter(|candidate| candidate.worker_url != removed_url)
+            .cloned()
+            .collect();
+
+        let mut moved = 0;
+        for key in (0..1000).map(|key| format!("client-{}", key)) {
+            let before = all[ConsistentHash.choose(&all, &context(0, Some(&key))).unwrap()].worker_url;
+            let after = remaining[ConsistentHash
+                .choose(&remaining, &context(0, Some(&key)))
+                .unwrap()]
+            .worker_url;
+
+            if before == removed_url {
+                moved += 1;
+            } else {
+                assert_eq!(before, after, "{} moved off a worker that's still there", key);
+            }
+        }
+
+        // And the removed worker did have its share of the keys to give up
+        assert!(
+            moved > 100,
+            "only {} of 1000 keys were on the removed worker",
+            moved
+        );
+    }
+}
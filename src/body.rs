@@ -0,0 +1,152 @@
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::{Async, Poll};
+use hyper::rt::{Future, Stream};
+use hyper::{Body, Chunk};
+use reqwest::r#async::Body as WorkerBody;
+
+use crate::error::RouterError;
+use crate::worker::InFlightRequest;
+
+// Bodies up to this size are buffered, so they can be resent if we need to retry on another worker
+const REPLAY_BUFFER_LIMIT: u64 = 64 * 1024;
+
+pub enum RequestBody {
+    Buffered(Bytes),
+    // Anything bigger (or of unknown size) is streamed straight through, so it can only be sent once
+    Streaming(LimitedBody<Body>),
+    Consumed,
+}
+
+impl RequestBody {
+    // Decides how to pass a client's body along, rejecting it up front if it's declared as too large
+    pub fn from_client(
+        body: Body,
+        content_length: Option<u64>,
+        max_bytes: u64,
+    ) -> impl Future<Item = Self, Error = RouterError> {
+        match content_length {
+            Some(content_length) if content_length > max_bytes => {
+                Either::A(future::err(RouterError::RequestBodyTooLarge))
+            }
+            Some(content_length) if content_length <= REPLAY_BUFFER_LIMIT => Either::B(Either::A(
+                body.concat2()
+                    .map(|chunk| RequestBody::Buffered(chunk.into_bytes()))
+                    .map_err(RouterError::from),
+            )),
+            _ => Either::B(Either::B(future::ok(RequestBody::Streaming(LimitedBody::new(
+                body, max_bytes,
+            ))))),
+        }
+    }
+
+    pub fn is_replayable(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    // Hands out the body for one attempt at sending it, or `None` if it was already streamed to someone
+    pub fn take_for_worker(&mut self) -> Option<WorkerBody> {
+        match mem::replace(self, RequestBody::Consumed) {
+            RequestBody::Buffered(bytes) => {
+                *self = RequestBody::Buffered(bytes.clone());
+                Some(WorkerBody::from(bytes))
+            }
+            RequestBody::Streaming(body) => {
+                let stream: Box<dyn Stream<Item = Chunk, Error = io::Error> + Send> = Box::new(body);
+                Some(WorkerBody::from(stream))
+            }
+            RequestBody::Consumed => None,
+        }
+    }
+
+    // Only streamed bodies can blow the limit midway through (buffered ones are checked before we read them)
+    pub fn limit_exceeded_flag(&self) -> Option<Arc<AtomicBool>> {
+        match self {
+            RequestBody::Streaming(body) => Some(body.limit_exceeded()),
+            _ => None,
+        }
+    }
+}
+
+// A body stream that errors out once more than `max_bytes` have gone through it
+// (so an oversized body is cut off while streaming, instead of after it has been read into memory)
+pub struct LimitedBody<S> {
+    inner: S,
+    max_bytes: u64,
+    seen_bytes: u64,
+    limit_exceeded: Arc<AtomicBool>,
+}
+
+impl<S> LimitedBody<S> {
+    pub fn new(inner: S, max_bytes: u64) -> Self {
+        Self {
+            inner,
+            max_bytes,
+            seen_bytes: 0,
+            limit_exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn limit_exceeded(&self) -> Arc<AtomicBool> {
+        self.limit_exceeded.clone()
+    }
+}
+
+impl<S> Stream for LimitedBody<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let chunk = match self.inner.poll() {
+            Ok(Async::Ready(Some(chunk))) => chunk,
+            Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        self.seen_bytes += chunk.as_ref().len() as u64;
+        if self.seen_bytes > self.max_bytes {
+            self.limit_exceeded.store(true, Ordering::SeqCst);
+            return Err(io::Error::other(format!(
+                "body exceeded the {} byte limit",
+                self.max_bytes
+            )));
+        }
+
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+// A response body that keeps its request counted as in flight to the worker until the body is done with
+pub struct InFlightBody<S> {
+    inner: S,
+    _in_flight: InFlightRequest,
+}
+
+impl<S> InFlightBody<S> {
+    pub fn new(inner: S, in_flight: InFlightRequest) -> Self {
+        Self {
+            inner,
+            _in_flight: in_flight,
+        }
+    }
+}
+
+impl<S: Stream> Stream for InFlightBody<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::CircuitBreakerConfig;
+use crate::metrics;
+use crate::model::ComponentPath;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // Requests go through as usual
+    Closed,
+    // Requests go elsewhere (or fail fast if there's nowhere else) until the breaker has been open for `open_secs`
+    Open,
+    // A few trial requests go through, the first of them to finish closes the breaker again or re-opens it
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+
+    fn gauge_value(self) -> f64 {
+        match self {
+            Self::Closed => 0.0,
+            Self::HalfOpen => 1.0,
+            Self::Open => 2.0,
+        }
+    }
+}
+
+// Stops sending requests somewhere they've been failing, so they don't each wait out a timeout to find that out
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u64,
+    open_duration: Duration,
+    half_open_max_requests: usize,
+    worker_url: String,
+    // `None` for the breaker covering the whole worker
+    component: Option<ComponentPath>,
+    component_label: String,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u64,
+    opened_at: Option<Instant>,
+    // Trial requests sent since the breaker went half open, and when the last one was
+    trials: usize,
+    last_trial: Option<Instant>,
+}
+
+// Everything the admin API reports about a breaker
+#[derive(Debug, Serialize)]
+pub struct CircuitBreakerStatus {
+    state: CircuitState,
+    consecutive_failures: u64,
+    opened_secs_ago: Option<f64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        config: &CircuitBreakerConfig,
+        worker_url: &str,
+        component: Option<&ComponentPath>,
+    ) -> Self {
+        let component_label =
+            component.map_or_else(|| metrics::ALL_COMPONENTS.to_string(), metrics::component_label);
+        metrics::CIRCUIT_BREAKER_STATE
+            .with_label_values(&[worker_url, &component_label])
+            .set(CircuitState::Closed.gauge_value());
+
+        Self {
+            failure_threshold: config.failure_threshold,
+            open_duration: config.open_duration(),
+            half_open_max_requests: config.half_open_max_requests,
+            worker_url: worker_url.to_string(),
+            component: component.cloned(),
+            component_label,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                trials: 0,
+                last_trial: None,
+            }),
+        }
+    }
+
+    // Whether a request can be sent through, checked and counted in one go when a worker is picked, so once the breaker
+    // is half open no more than `half_open_max_requests` trials get through however many requests arrive at once
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        match state.state {
+            CircuitState::Closed => return true,
+            CircuitState::Open if !self.open_time_elapsed(state.opened_at) => return false,
+            CircuitState::Open => {
+                info!("{} is half open, letting trial requests through", self.name());
+                self.transition(&mut state, CircuitState::HalfOpen);
+                state.trials = 0;
+            }
+            // A trial that never reported back (e.g. the client went away) doesn't hold the breaker half open forever,
+            // once the last one is `open_secs` old they're all taken as lost
+            CircuitState::HalfOpen if state.trials >= self.half_open_max_requests => {
+                if !self.open_time_elapsed(state.last_trial) {
+                    return false;
+                }
+                state.trials = 0;
+            }
+            CircuitState::HalfOpen => {}
+        }
+
+        state.trials += 1;
+        state.last_trial = Some(Instant::now());
+        true
+    }
+
+    // Gives back what `try_acquire` handed out, for a request that ended up not being sent through after all
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        if state.state == CircuitState::HalfOpen {
+            state.trials = state.trials.saturating_sub(1);
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock();
+        match state.state {
+            CircuitState::Closed => state.consecutive_failures = 0,
+            CircuitState::HalfOpen => {
+                info!("{} is closed again, a trial request succeeded", self.name());
+                self.transition(&mut state, CircuitState::Closed);
+                state.consecutive_failures = 0;
+                state.opened_at = None;
+            }
+            // Sent before the breaker opened, it says nothing about how things are now
+            CircuitState::Open => {}
+        }
+    }
+
+    pub fn record_failure(&self, reason: &dyn Display) {
+        let mut state = self.state.lock();
+        state.consecutive_failures += 1;
+        match state.state {
+            CircuitState::Closed if state.consecutive_failures >= self.failure_threshold => {
+                warn!(
+                    "{} opened after {} failures in a row: {}",
+                    self.name(),
+                    state.consecutive_failures,
+                    reason
+                );
+                self.transition(&mut state, CircuitState::Open);
+                state.opened_at = Some(Instant::now());
+            }
+            CircuitState::HalfOpen => {
+                warn!("{} opened again, a trial request failed: {}", self.name(), reason);
+                self.transition(&mut state, CircuitState::Open);
+                state.opened_at = Some(Instant::now());
+            }
+            CircuitState::Closed | CircuitState::Open => {}
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state.lock().state
+    }
+
+    pub fn status(&self) -> CircuitBreakerStatus {
+        let state = self.state.lock();
+
+        CircuitBreakerStatus {
+            state: state.state,
+            consecutive_failures: state.consecutive_failures,
+            opened_secs_ago: state.opened_at.map(|opened_at| opened_at.elapsed().as_secs_f64()),
+        }
+    }
+
+    // Drops the series that would otherwise keep reporting a removed worker's breaker
+    pub fn forget_metrics(&self) {
+        let _ = metrics::CIRCUIT_BREAKER_STATE
+            .remove_label_values(&[&self.worker_url, &self.component_label]);
+    }
+
+    fn open_time_elapsed(&self, since: Option<Instant>) -> bool {
+        since.is_none_or(|since| since.elapsed() >= self.open_duration)
+    }
+
+    fn transition(&self, state: &mut BreakerState, new_state: CircuitState) {
+        state.state = new_state;
+        metrics::CIRCUIT_BREAKER_STATE
+            .with_label_values(&[&self.worker_url, &self.component_label])
+            .set(new_state.gauge_value());
+        metrics::CIRCUIT_BREAKER_TRANSITIONS
+            .with_label_values(&[&self.worker_url, &self.component_label, new_state.label()])
+            .inc();
+    }
+
+    fn name(&self) -> String {
+        match &self.component {
+            Some(path) => format!("Circuit breaker for {} on worker {}", path, self.worker_url),
+            None => format!("Circuit breaker for worker {}", self.worker_url),
+        }
+    }
+}
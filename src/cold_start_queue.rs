@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use hyper::rt::Future;
+use parking_lot::Mutex;
+use tokio::util::FutureExt;
+
+use crate::config::ColdStartQueueConfig;
+use crate::error::RouterError;
+use crate::metrics;
+use crate::model::ComponentPath;
+
+// Requests for components that aren't in the component map yet, parked until a refresh brings them in
+// (a freshly deployed component is otherwise a 404 until the next scheduled refresh)
+#[derive(Debug)]
+pub struct ColdStartQueue {
+    max_per_component: usize,
+    max_total: usize,
+    timeout: Duration,
+    waiting: Mutex<HashMap<ComponentPath, Vec<oneshot::Sender<()>>>>,
+}
+
+impl ColdStartQueue {
+    pub fn new(config: &ColdStartQueueConfig) -> Self {
+        Self {
+            max_per_component: config.max_per_component,
+            max_total: config.max_total,
+            timeout: config.timeout(),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Resolves once the component is in the component map, fails straight away if the queue is full
+    // Note: `is_ready` is checked again with the queue locked, since a map published after the caller last looked (but
+    // before we park the request) would otherwise wake everyone but us
+    pub fn wait_for(
+        &self,
+        path: &ComponentPath,
+        is_ready: &dyn Fn(&ComponentPath) -> bool,
+    ) -> impl Future<Item = (), Error = RouterError> + Send {
+        let mut waiting = self.waiting.lock();
+        if is_ready(path) {
+            metrics::COLD_START_QUEUE.with_label_values(&["ready"]).inc();
+            return Either::A(future::ok(()));
+        }
+
+        // Requests that stopped waiting (they timed out, or the client went away) don't take up space
+        for senders in waiting.values_mut() {
+            senders.retain(|sender| !sender.is_canceled());
+        }
+        waiting.retain(|_, senders| !senders.is_empty());
+
+        let total_waiting: usize = waiting.values().map(Vec::len).sum();
+        let component_waiting = waiting.get(path).map_or(0, Vec::len);
+        if component_waiting >= self.max_per_component || total_waiting >= self.max_total {
+            metrics::COLD_START_QUEUE.with_label_values(&["overflow"]).inc();
+            return Either::A(future::err(RouterError::ColdStartQueueFull(path.to_string())));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        waiting.entry(path.clone()).or_default().push(sender);
+        let path = path.clone();
+
+        Either::B(receiver.timeout(self.timeout).then(move |result| match result {
+            Ok(()) => {
+                metrics::COLD_START_QUEUE.with_label_values(&["ready"]).inc();
+                Ok(())
+            }
+            Err(ref e) if e.is_elapsed() => {
+                metrics::COLD_START_QUEUE.with_label_values(&["timeout"]).inc();
+                Err(RouterError::ColdStartTimeout(path.to_string()))
+            }
+            // The queue itself went away (or the timer broke), either way we're not going to hear back
+            Err(_) => Err(RouterError::PathNotFound(format!("no such component: {}", path))),
+        }))
+    }
+
+    // Called after every component map update, with whether each component is in the new map
+    pub fn wake_ready(&self, is_ready: impl Fn(&ComponentPath) -> bool) {
+        self.waiting.lock().retain(|path, senders| {
+            if !is_ready(path) {
+                return true;
+            }
+
+            for sender in senders.drain(..) {
+                let _ = sender.send(());
+            }
+            false
+        });
+    }
+}
@@ -0,0 +1,913 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use structopt::StructOpt;
+use toml::Value;
+
+use crate::error::RouterError;
+use crate::model::ComponentPath;
+
+// Everything the router can be configured with, layered as:
+// defaults < profile preset (e.g. `--development`) < TOML config file < environment variables < command line flags
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    pub workers: Vec<String>,
+    pub server: ServerConfig,
+    pub load_balancer: LoadBalancerConfig,
+    pub health: HealthConfig,
+    pub timeouts: TimeoutConfig,
+    pub limits: BodyLimitConfig,
+    pub logging: LoggingConfig,
+    pub admin: AdminConfig,
+    pub activation: ActivationConfig,
+    pub reaper: ReaperConfig,
+    pub cold_start_queue: ColdStartQueueConfig,
+    pub push: PushConfig,
+    pub retry: RetryConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadBalancerConfig {
+    pub mode: BalancingMode,
+    // Components that should be balanced differently to everything else, keyed by "user/repo"
+    pub overrides: BTreeMap<String, BalancingMode>,
+    // Components whose requests should keep landing on the same worker, keyed by "user/repo"
+    pub sticky: BTreeMap<String, StickyConfig>,
+    // Components whose traffic is split between versions, keyed by "user/repo", each mapping a hash to its percentage
+    pub splits: BTreeMap<String, TrafficSplit>,
+    // Under `least_latency`, how much our own latency measurements count against what the workers report (0.0 to 1.0)
+    pub observed_latency_weight: f64,
+    // During a rollout, requests move to the newest version once it's up on at least this many available workers
+    pub min_workers_for_new_version: usize,
+    // This is sensitive to how quickly the deployment manager makes changes
+    pub refresh_interval_secs: u64,
+    // How long a worker that stops answering status polls keeps its last known components
+    pub worker_grace_period_secs: u64,
+    // What to do when a worker says it doesn't have a component the component map says it does
+    pub stale_data: StaleDataMode,
+    // Stale data only triggers a refresh if the last one started at least this long ago (joining one underway is free)
+    pub stale_refresh_min_interval_ms: u64,
+}
+
+// Version hash -> share of the traffic (these are relative weights, so they don't have to add up to exactly 100)
+pub type TrafficSplit = BTreeMap<String, u32>;
+
+// Where to find the key a request sticks by, the first of these the request actually has is used
+// (requests with none of them are balanced like any other request for the component)
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickyConfig {
+    pub cookie: Option<String>,
+    pub header: Option<String>,
+    pub query: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingMode {
+    RoundRobin,
+    Random,
+    // Weighted by the resource usage each worker reports, so a hot worker gets a smaller share
+    #[serde(alias = "weighted")]
+    LoadAware,
+    // Power of two choices, picking whichever of two random workers has been answering the component faster
+    LeastLatency,
+    // Whichever worker we have the fewest requests in flight to
+    LeastOutstanding,
+    // The same client keeps going to the same worker
+    ConsistentHash,
+}
+
+impl FromStr for BalancingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            "load_aware" | "weighted" => Ok(Self::LoadAware),
+            "least_latency" => Ok(Self::LeastLatency),
+            "least_outstanding" => Ok(Self::LeastOutstanding),
+            "consistent_hash" => Ok(Self::ConsistentHash),
+            _ => Err(format!(
+                "unknown balancing mode {:?} (expected round_robin, random, load_aware, least_latency, \
+                 least_outstanding or consistent_hash)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaleDataMode {
+    // Poll every worker again (rate limited by `stale_refresh_min_interval_ms`)
+    Refresh,
+    // Just take that worker out of the component's version straight away, the next refresh puts it back if it's wrong
+    Evict,
+}
+
+impl FromStr for StaleDataMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refresh" => Ok(Self::Refresh),
+            "evict" => Ok(Self::Evict),
+            _ => Err(format!(
+                "unknown stale data mode {:?} (expected refresh or evict)",
+                s
+            )),
+        }
+    }
+}
+
+// Status polls and forwarded requests both count, a worker is ejected after `unhealthy_threshold` failures in a row,
+// then re-admitted after `healthy_threshold` successes in a row (which can only come from status polls once it's out)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    pub unhealthy_threshold: u64,
+    pub healthy_threshold: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub worker_status_secs: u64,
+    pub forward_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitConfig {
+    pub max_request_body_bytes: u64,
+    pub max_response_body_bytes: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub spec: String,
+}
+
+// The admin API has no authentication, so it only listens on localhost unless told otherwise
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+// Scale from zero: requests for components no worker is running can activate them on a worker, going by the registry
+// file (which says what to run for each component), activation is off unless a registry file is set
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActivationConfig {
+    pub registry_file: Option<PathBuf>,
+    // How long a request waits for its component to be activated and show up in the component map
+    pub cold_start_timeout_secs: u64,
+}
+
+// Deactivates replicas that haven't had a request from us in `idle_ttl_secs`, but never takes a component (all of its
+// versions together) below `min_replicas` available replicas (0 lets idle components scale to zero, to be activated
+// again on demand)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReaperConfig {
+    pub enabled: bool,
+    pub idle_ttl_secs: u64,
+    pub min_replicas: usize,
+    pub check_interval_secs: u64,
+}
+
+// Requests for components we don't know about yet wait (up to `timeout_secs`) for a component map refresh to bring
+// them in, instead of getting a 404 straight away
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColdStartQueueConfig {
+    pub enabled: bool,
+    pub max_per_component: usize,
+    // Across every component, so a flood of requests for made up components can't pile up without limit
+    pub max_total: usize,
+    pub timeout_secs: u64,
+}
+
+// How requests that fail on one worker are tried again on another, only requests the worker provably never got
+// (e.g. the connection was refused) are retried for non-idempotent verbs like POST
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    // Including the first try, so 1 turns retries off
+    pub max_attempts: u32,
+    // Until the worker's response headers arrive, left out it's `timeouts.forward_secs`
+    pub per_try_timeout_ms: Option<u64>,
+    // Before the first retry, doubling for every retry after that (up to `max_backoff_ms`)
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub retry_on: Vec<RetryOn>,
+    // Components that should be retried differently to everything else, keyed by "user/repo"
+    pub overrides: BTreeMap<String, RetryOverride>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    // Couldn't connect to the worker at all
+    ConnectFailure,
+    // No response headers within the per try timeout
+    Timeout,
+    // The worker answered 503
+    Unavailable,
+    // The worker answered `v9: worker 404`, it doesn't have the component after all
+    StaleData,
+}
+
+// Anything left out is taken from `[retry]`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryOverride {
+    pub max_attempts: Option<u32>,
+    pub per_try_timeout_ms: Option<u64>,
+    pub backoff_ms: Option<u64>,
+    pub max_backoff_ms: Option<u64>,
+    pub retry_on: Option<Vec<RetryOn>>,
+}
+
+// Forwarded requests that fail trip a breaker for the worker (if it couldn't be reached at all, or timed out) and for the
+// component on that worker (unless it couldn't be reached), an open breaker takes them out of selection for
+// `open_secs`, then lets `half_open_max_requests` through to see if they've recovered
+// Note: On by default, so upgrading changes routing for anyone who doesn't set `enabled = false`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    // Failures in a row that open a closed breaker
+    pub failure_threshold: u64,
+    pub open_secs: u64,
+    pub half_open_max_requests: usize,
+}
+
+// The deployment manager (or a worker) can push component changes to `POST /meta/component-events` on the main
+// listener, authenticated with this token (pushes are turned off unless it's set)
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PushConfig {
+    pub token: Option<String>,
+}
+
+// The whole config gets logged at startup, so keep the token out of it
+impl Debug for PushConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("PushConfig")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Router application for the Velocity 9 serverless platform")]
+struct CommandLine {
+    /// TOML config file to load
+    #[structopt(long, env = "V9_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Use the development profile (serves on port 8080, for running behind a TLS reverse proxy)
+    #[structopt(long)]
+    development: bool,
+
+    /// Semicolon separated list of worker URLs
+    #[structopt(long, env = "V9_WORKERS")]
+    workers: Option<String>,
+
+    #[structopt(long, env = "V9_BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+
+    #[structopt(long, env = "V9_PORT")]
+    port: Option<u16>,
+
+    /// How to pick between workers running the same component: `round_robin`, `random`, `load_aware`, `least_latency`,
+    /// `least_outstanding` or `consistent_hash`
+    #[structopt(long, env = "V9_BALANCING_MODE")]
+    balancing_mode: Option<BalancingMode>,
+
+    #[structopt(long, env = "V9_OBSERVED_LATENCY_WEIGHT")]
+    observed_latency_weight: Option<f64>,
+
+    #[structopt(long, env = "V9_MIN_WORKERS_FOR_NEW_VERSION")]
+    min_workers_for_new_version: Option<usize>,
+
+    #[structopt(long, env = "V9_REFRESH_INTERVAL_SECS")]
+    refresh_interval_secs: Option<u64>,
+
+    #[structopt(long, env = "V9_WORKER_GRACE_PERIOD_SECS")]
+    worker_grace_period_secs: Option<u64>,
+
+    /// What to do when a worker turns out not to have a component: `refresh` or `evict`
+    #[structopt(long, env = "V9_STALE_DATA_MODE")]
+    stale_data_mode: Option<StaleDataMode>,
+
+    #[structopt(long, env = "V9_STALE_REFRESH_MIN_INTERVAL_MS")]
+    stale_refresh_min_interval_ms: Option<u64>,
+
+    #[structopt(long, env = "V9_UNHEALTHY_THRESHOLD")]
+    unhealthy_threshold: Option<u64>,
+
+    #[structopt(long, env = "V9_HEALTHY_THRESHOLD")]
+    healthy_threshold: Option<u64>,
+
+    #[structopt(long, env = "V9_WORKER_STATUS_TIMEOUT_SECS")]
+    worker_status_timeout_secs: Option<u64>,
+
+    #[structopt(long, env = "V9_FORWARD_TIMEOUT_SECS")]
+    forward_timeout_secs: Option<u64>,
+
+    #[structopt(long, env = "V9_MAX_REQUEST_BODY_BYTES")]
+    max_request_body_bytes: Option<u64>,
+
+    #[structopt(long, env = "V9_MAX_RESPONSE_BODY_BYTES")]
+    max_response_body_bytes: Option<u64>,
+
+    /// Log specification, e.g. "info, hyper=warn"
+    #[structopt(long, env = "V9_LOG_SPEC")]
+    log_spec: Option<String>,
+
+    #[structopt(long, env = "V9_ADMIN_BIND_ADDRESS")]
+    admin_bind_address: Option<IpAddr>,
+
+    #[structopt(long, env = "V9_ADMIN_PORT")]
+    admin_port: Option<u16>,
+
+    /// Component registry file, setting this turns on activating components on demand
+    #[structopt(long, env = "V9_ACTIVATION_REGISTRY_FILE", parse(from_os_str))]
+    activation_registry_file: Option<PathBuf>,
+
+    #[structopt(long, env = "V9_COLD_START_TIMEOUT_SECS")]
+    cold_start_timeout_secs: Option<u64>,
+
+    /// Token the deployment manager / workers push component events with, setting this turns pushes on
+    #[structopt(long, env = "V9_PUSH_TOKEN", hide_env_values = true)]
+    push_token: Option<String>,
+
+    #[structopt(long, env = "V9_CIRCUIT_BREAKER_FAILURE_THRESHOLD")]
+    circuit_breaker_failure_threshold: Option<u64>,
+
+    #[structopt(long, env = "V9_CIRCUIT_BREAKER_OPEN_SECS")]
+    circuit_breaker_open_secs: Option<u64>,
+
+    #[structopt(long, env = "V9_REAPER_IDLE_TTL_SECS")]
+    reaper_idle_ttl_secs: Option<u64>,
+
+    #[structopt(long, env = "V9_REAPER_MIN_REPLICAS")]
+    reaper_min_replicas: Option<usize>,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            workers: Vec::new(),
+            server: ServerConfig {
+                bind_address: IpAddr::from([0, 0, 0, 0]),
+                port: 80,
+            },
+            load_balancer: LoadBalancerConfig {
+                mode: BalancingMode::RoundRobin,
+                overrides: BTreeMap::new(),
+                sticky: BTreeMap::new(),
+                splits: BTreeMap::new(),
+                observed_latency_weight: 0.5,
+                min_workers_for_new_version: 1,
+                refresh_interval_secs: 5,
+                worker_grace_period_secs: 30,
+                stale_data: StaleDataMode::Refresh,
+                stale_refresh_min_interval_ms: 1000,
+            },
+            health: HealthConfig {
+                unhealthy_threshold: 3,
+                healthy_threshold: 2,
+            },
+            timeouts: TimeoutConfig {
+                worker_status_secs: 3,
+                forward_secs: 3,
+            },
+            limits: BodyLimitConfig {
+                max_request_body_bytes: 100 * 1024 * 1024,
+                max_response_body_bytes: 100 * 1024 * 1024,
+            },
+            logging: LoggingConfig {
+                spec: "debug, hyper=info, mio=info, tokio_reactor=info, tokio_threadpool=info"
+                    .to_string(),
+            },
+            admin: AdminConfig {
+                enabled: true,
+                bind_address: IpAddr::from([127, 0, 0, 1]),
+                port: 9090,
+            },
+            activation: ActivationConfig {
+                registry_file: None,
+                cold_start_timeout_secs: 30,
+            },
+            reaper: ReaperConfig {
+                enabled: false,
+                idle_ttl_secs: 15 * 60,
+                min_replicas: 1,
+                check_interval_secs: 60,
+            },
+            cold_start_queue: ColdStartQueueConfig {
+                enabled: false,
+                max_per_component: 100,
+                max_total: 1000,
+                timeout_secs: 10,
+            },
+            push: PushConfig::default(),
+            retry: RetryConfig {
+                max_attempts: 2,
+                per_try_timeout_ms: None,
+                backoff_ms: 25,
+                max_backoff_ms: 1000,
+                retry_on: vec![
+                    RetryOn::ConnectFailure,
+                    RetryOn::Timeout,
+                    RetryOn::Unavailable,
+                    RetryOn::StaleData,
+                ],
+                overrides: BTreeMap::new(),
+            },
+            circuit_breaker: CircuitBreakerConfig {
+                enabled: true,
+                failure_threshold: 5,
+                open_secs: 10,
+                half_open_max_requests: 1,
+            },
+        }
+    }
+}
+
+impl RouterConfig {
+    // Builds the config from the command line, environment and config file, then checks it makes sense
+    // (this is also how a running router reloads, the command line and environment can't have changed since startup)
+    pub fn load() -> Result<Self, RouterError> {
+        let command_line = CommandLine::from_args();
+
+        let mut config = Self::default();
+        if command_line.development {
+            config.apply_development_profile();
+        }
+
+        if let Some(path) = &command_line.config {
+            config = config.merge_file(path)?;
+        }
+
+        config.apply_command_line(command_line);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Whether `workers` is coming from `--workers` / `V9_WORKERS`, which hide whatever the config file says
+    pub fn workers_from_command_line() -> bool {
+        CommandLine::from_args().workers.is_some()
+    }
+
+    fn apply_development_profile(&mut self) {
+        self.server.port = 8080;
+    }
+
+    // Values in the file replace the ones we already have, anything the file leaves out is kept
+    fn merge_file(self, path: &PathBuf) -> Result<Self, RouterError> {
+        let file_contents =
+            fs::read_to_string(path).map_err(|e| RouterError::ConfigFile(path.clone(), e))?;
+        let file_value: Value = toml::from_str(&file_contents)?;
+
+        let mut merged =
+            Value::try_from(self).expect("The router config can always be represented as TOML");
+        merge_toml(&mut merged, file_value);
+
+        Ok(merged.try_into()?)
+    }
+
+    // Environment variables come in through here too, since every flag can also be set from one
+    fn apply_command_line(&mut self, command_line: CommandLine) {
+        if let Some(workers) = command_line.workers {
+            self.workers = workers
+                .split(';')
+                .filter(|worker_url| !worker_url.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Some(bind_address) = command_line.bind_address {
+            self.server.bind_address = bind_address;
+        }
+        if let Some(port) = command_line.port {
+            self.server.port = port;
+        }
+
+        if let Some(mode) = command_line.balancing_mode {
+            self.load_balancer.mode = mode;
+        }
+        if let Some(weight) = command_line.observed_latency_weight {
+            self.load_balancer.observed_latency_weight = weight;
+        }
+        if let Some(min_workers) = command_line.min_workers_for_new_version {
+            self.load_balancer.min_workers_for_new_version = min_workers;
+        }
+        if let Some(secs) = command_line.refresh_interval_secs {
+            self.load_balancer.refresh_interval_secs = secs;
+        }
+        if let Some(secs) = command_line.worker_grace_period_secs {
+            self.load_balancer.worker_grace_period_secs = secs;
+        }
+        if let Some(mode) = command_line.stale_data_mode {
+            self.load_balancer.stale_data = mode;
+        }
+        if let Some(ms) = command_line.stale_refresh_min_interval_ms {
+            self.load_balancer.stale_refresh_min_interval_ms = ms;
+        }
+
+        if let Some(threshold) = command_line.unhealthy_threshold {
+            self.health.unhealthy_threshold = threshold;
+        }
+        if let Some(threshold) = command_line.healthy_threshold {
+            self.health.healthy_threshold = threshold;
+        }
+
+        if let Some(secs) = command_line.worker_status_timeout_secs {
+            self.timeouts.worker_status_secs = secs;
+        }
+        if let Some(secs) = command_line.forward_timeout_secs {
+            self.timeouts.forward_secs = secs;
+        }
+
+        if let Some(bytes) = command_line.max_request_body_bytes {
+            self.limits.max_request_body_bytes = bytes;
+        }
+        if let Some(bytes) = command_line.max_response_body_bytes {
+            self.limits.max_response_body_bytes = bytes;
+        }
+
+        if let Some(spec) = command_line.log_spec {
+            self.logging.spec = spec;
+        }
+
+        if let Some(bind_address) = command_line.admin_bind_address {
+            self.admin.bind_address = bind_address;
+        }
+        if let Some(port) = command_line.admin_port {
+            self.admin.port = port;
+        }
+
+        if let Some(registry_file) = command_line.activation_registry_file {
+            self.activation.registry_file = Some(registry_file);
+        }
+        if let Some(secs) = command_line.cold_start_timeout_secs {
+            self.activation.cold_start_timeout_secs = secs;
+        }
+
+        if let Some(token) = command_line.push_token {
+            self.push.token = Some(token);
+        }
+
+        if let Some(threshold) = command_line.circuit_breaker_failure_threshold {
+            self.circuit_breaker.failure_threshold = threshold;
+        }
+        if let Some(secs) = command_line.circuit_breaker_open_secs {
+            self.circuit_breaker.open_secs = secs;
+        }
+
+        if let Some(secs) = command_line.reaper_idle_ttl_secs {
+            self.reaper.idle_ttl_secs = secs;
+        }
+        if let Some(min_replicas) = command_line.reaper_min_replicas {
+            self.reaper.min_replicas = min_replicas;
+        }
+    }
+
+    fn validate(&self) -> Result<(), RouterError> {
+        if self.workers.is_empty() {
+            return Err(RouterError::InvalidConfig(
+                "no workers configured (set `workers` in the config file, --workers or V9_WORKERS)"
+                    .to_string(),
+            ));
+        }
+
+        validate_worker_urls(&self.workers).map_err(RouterError::InvalidConfig)?;
+
+        self.validate_component_keys()?;
+
+        for (component, sticky) in &self.load_balancer.sticky {
+            if sticky.cookie.is_none() && sticky.header.is_none() && sticky.query.is_none() {
+                return Err(RouterError::InvalidConfig(format!(
+                    "load_balancer.sticky.\"{}\" needs at least one of cookie, header or query",
+                    component
+                )));
+            }
+        }
+
+        for (component, split) in &self.load_balancer.splits {
+            validate_traffic_split(split).map_err(|e| {
+                RouterError::InvalidConfig(format!("load_balancer.splits.\"{}\" {}", component, e))
+            })?;
+        }
+
+        self.retry.validate().map_err(RouterError::InvalidConfig)?;
+        self.circuit_breaker
+            .validate()
+            .map_err(RouterError::InvalidConfig)?;
+
+        if self.push.token.as_ref().is_some_and(String::is_empty) {
+            return Err(RouterError::InvalidConfig(
+                "push.token can't be empty (leave it out to turn pushes off)".to_string(),
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.load_balancer.observed_latency_weight) {
+            return Err(RouterError::InvalidConfig(
+                "load_balancer.observed_latency_weight must be between 0.0 and 1.0".to_string(),
+            ));
+        }
+
+        let thresholds = [
+            ("health.unhealthy_threshold", self.health.unhealthy_threshold),
+            ("health.healthy_threshold", self.health.healthy_threshold),
+            (
+                "load_balancer.min_workers_for_new_version",
+                self.load_balancer.min_workers_for_new_version as u64,
+            ),
+            ("retry.max_attempts", u64::from(self.retry.max_attempts)),
+        ];
+        for (name, threshold) in &thresholds {
+            if *threshold == 0 {
+                return Err(RouterError::InvalidConfig(format!(
+                    "{} must be greater than zero",
+                    name
+                )));
+            }
+        }
+
+        let durations = [
+            (
+                "load_balancer.refresh_interval_secs",
+                self.load_balancer.refresh_interval_secs,
+            ),
+            ("timeouts.worker_status_secs", self.timeouts.worker_status_secs),
+            ("timeouts.forward_secs", self.timeouts.forward_secs),
+            (
+                "activation.cold_start_timeout_secs",
+                self.activation.cold_start_timeout_secs,
+            ),
+            ("reaper.idle_ttl_secs", self.reaper.idle_ttl_secs),
+            ("reaper.check_interval_secs", self.reaper.check_interval_secs),
+            (
+                "cold_start_queue.timeout_secs",
+                self.cold_start_queue.timeout_secs,
+            ),
+        ];
+        for (name, secs) in &durations {
+            if *secs == 0 {
+                return Err(RouterError::InvalidConfig(format!(
+                    "{} must be greater than zero",
+                    name
+                )));
+            }
+        }
+
+        if let Err(e) = flexi_logger::LogSpecification::parse(&self.logging.spec) {
+            return Err(RouterError::InvalidConfig(format!(
+                "invalid logging.spec {:?}: {}",
+                self.logging.spec, e
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Every per component table is keyed by "user/repo"
+    fn validate_component_keys(&self) -> Result<(), RouterError> {
+        let component_keys = [
+            (
+                "load_balancer.overrides",
+                self.load_balancer.overrides.keys().collect::<Vec<_>>(),
+            ),
+            ("load_balancer.sticky", self.load_balancer.sticky.keys().collect()),
+            ("load_balancer.splits", self.load_balancer.splits.keys().collect()),
+            ("retry.overrides", self.retry.overrides.keys().collect()),
+        ];
+        for (name, components) in &component_keys {
+            for component in components {
+                if parse_component_path(component).is_none() {
+                    return Err(RouterError::InvalidConfig(format!(
+                        "{} keys must look like \"user/repo\", got {:?}",
+                        name, component
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.server.bind_address, self.server.port)
+    }
+
+    pub fn admin_address(&self) -> SocketAddr {
+        SocketAddr::new(self.admin.bind_address, self.admin.port)
+    }
+}
+
+impl LoadBalancerConfig {
+    // Note: The keys were checked when the config was loaded, so they all parse
+    pub fn component_overrides(&self) -> Vec<(ComponentPath, BalancingMode)> {
+        self.overrides
+            .iter()
+            .filter_map(|(component, mode)| Some((parse_component_path(component)?, *mode)))
+            .collect()
+    }
+
+    pub fn component_sticky(&self) -> Vec<(ComponentPath, StickyConfig)> {
+        self.sticky
+            .iter()
+            .filter_map(|(component, sticky)| Some((parse_component_path(component)?, sticky.clone())))
+            .collect()
+    }
+
+    pub fn component_splits(&self) -> Vec<(ComponentPath, TrafficSplit)> {
+        self.splits
+            .iter()
+            .filter_map(|(component, split)| Some((parse_component_path(component)?, split.clone())))
+            .collect()
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval_secs)
+    }
+
+    pub fn worker_grace_period(&self) -> Duration {
+        Duration::from_secs(self.worker_grace_period_secs)
+    }
+
+    pub fn stale_refresh_min_interval(&self) -> Duration {
+        Duration::from_millis(self.stale_refresh_min_interval_ms)
+    }
+}
+
+impl ActivationConfig {
+    pub fn cold_start_timeout(&self) -> Duration {
+        Duration::from_secs(self.cold_start_timeout_secs)
+    }
+}
+
+impl ReaperConfig {
+    pub fn idle_ttl(&self) -> Duration {
+        Duration::from_secs(self.idle_ttl_secs)
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs)
+    }
+}
+
+impl ColdStartQueueConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl RetryConfig {
+    // Note: `max_attempts` is checked along with the other thresholds
+    fn validate(&self) -> Result<(), String> {
+        if self.per_try_timeout_ms == Some(0) {
+            return Err(
+                "retry.per_try_timeout_ms must be greater than zero (leave it out to use timeouts.forward_secs)"
+                    .to_string(),
+            );
+        }
+
+        for (component, retry_override) in &self.overrides {
+            if retry_override.max_attempts == Some(0) {
+                return Err(format!(
+                    "retry.overrides.\"{}\".max_attempts must be greater than zero",
+                    component
+                ));
+            }
+            if retry_override.per_try_timeout_ms == Some(0) {
+                return Err(format!(
+                    "retry.overrides.\"{}\".per_try_timeout_ms must be greater than zero",
+                    component
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Note: The keys were checked when the config was loaded, so they all parse
+    pub fn component_overrides(&self) -> Vec<(ComponentPath, RetryOverride)> {
+        self.overrides
+            .iter()
+            .filter_map(|(component, retry)| Some((parse_component_path(component)?, retry.clone())))
+            .collect()
+    }
+}
+
+impl CircuitBreakerConfig {
+    fn validate(&self) -> Result<(), String> {
+        let settings = [
+            ("circuit_breaker.failure_threshold", self.failure_threshold),
+            ("circuit_breaker.open_secs", self.open_secs),
+            (
+                "circuit_breaker.half_open_max_requests",
+                self.half_open_max_requests as u64,
+            ),
+        ];
+        for (name, value) in &settings {
+            if *value == 0 {
+                return Err(format!("{} must be greater than zero", name));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.open_secs)
+    }
+}
+
+impl TimeoutConfig {
+    pub fn worker_status(&self) -> Duration {
+        Duration::from_secs(self.worker_status_secs)
+    }
+
+    pub fn forward(&self) -> Duration {
+        Duration::from_secs(self.forward_secs)
+    }
+}
+
+// Shared with the admin API, which can replace the worker list at runtime
+pub fn validate_worker_urls(worker_urls: &[String]) -> Result<(), String> {
+    for worker_url in worker_urls {
+        if let Err(e) = reqwest::Url::parse(worker_url) {
+            return Err(format!("invalid worker url {:?}: {}", worker_url, e));
+        }
+    }
+
+    Ok(())
+}
+
+// Shared with the admin API, which can change splits at runtime
+pub fn validate_traffic_split(split: &TrafficSplit) -> Result<(), String> {
+    if split.values().all(|weight| *weight == 0) {
+        return Err("needs at least one version with a weight above zero".to_string());
+    }
+    if split.keys().any(String::is_empty) {
+        return Err("has an empty version hash".to_string());
+    }
+
+    Ok(())
+}
+
+// Note: This is shared with the component registry, whose keys are "user/repo" too
+pub fn parse_component_path(component: &str) -> Option<ComponentPath> {
+    let mut parts = component.split('/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(user), Some(repo), None) if !user.is_empty() && !repo.is_empty() => Some(ComponentPath {
+            user: user.to_string(),
+            repo: repo.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+fn merge_toml(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base_table), Value::Table(overlay_table)) => {
+            for (key, overlay_value) in overlay_table {
+                match base_table.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, overlay_value),
+                    None => {
+                        base_table.insert(key, overlay_value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
@@ -1,39 +1,137 @@
 use std::fmt::{self, Display, Formatter};
-use std::str::Utf8Error;
+use std::io;
+use std::path::PathBuf;
 
 use hyper::{Body, Response};
+use tokio::timer::{self, timeout};
 
 #[derive(Debug, Fail)]
 pub enum RouterError {
+    ActivationFailed(String),
+    BodyStream(io::Error),
+    ColdStartQueueFull(String),
+    ColdStartTimeout(String),
+    ConfigFile(PathBuf, io::Error),
+    ConfigParse(toml::de::Error),
     Hyper(hyper::error::Error),
+    InvalidAdminRequest(String),
     InternalJsonHandling(serde_json::Error),
+    InvalidConfig(String),
+    InvalidPushEvent(String),
     InvalidRequest(reqwest::Error),
-    InvalidUtf8(Utf8Error),
+    Metrics(prometheus::Error),
+    NoAvailableWorker(String),
     PathNotFound(String),
+    RequestBodyTooLarge,
+    ResponseBodyTooLarge,
+    StalePushEvent(String),
+    Timer(timer::Error),
+    Unauthorized,
+    WorkerTimeout,
 }
 
 impl Display for RouterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
+            Self::ActivationFailed(msg) => {
+                write!(f, "RouterError, could not activate component: {}", msg)?;
+            }
+
+            Self::BodyStream(e) => {
+                write!(f, "RouterError, caused by an error streaming a body: {}", e)?;
+            }
+
+            Self::ColdStartQueueFull(p) => {
+                write!(
+                    f,
+                    "RouterError, too many requests already waiting for {} to start",
+                    p
+                )?;
+            }
+
+            Self::ColdStartTimeout(p) => {
+                write!(f, "RouterError, timed out waiting for {} to start", p)?;
+            }
+
+            Self::ConfigFile(path, e) => {
+                write!(
+                    f,
+                    "RouterError, could not read config file {}: {}",
+                    path.display(),
+                    e
+                )?;
+            }
+
+            Self::ConfigParse(e) => {
+                write!(f, "RouterError, could not parse config file: {}", e)?;
+            }
+
             Self::Hyper(e) => {
                 write!(f, "RouterError, caused by internal hyper error: {}", e)?;
             }
 
+            Self::InvalidAdminRequest(msg) => {
+                write!(f, "RouterError, invalid admin request: {}", msg)?;
+            }
+
             Self::InternalJsonHandling(e) => {
                 write!(f, "RouterError, caused by internal serde_json error: {}", e)?;
             }
 
+            Self::InvalidConfig(msg) => {
+                write!(f, "RouterError, invalid config: {}", msg)?;
+            }
+
+            Self::InvalidPushEvent(msg) => {
+                write!(f, "RouterError, invalid component event push: {}", msg)?;
+            }
+
             Self::InvalidRequest(e) => {
                 write!(f, "RouterError, caused by an invalid reqwest response: {}", e)?;
             }
 
-            Self::InvalidUtf8(e) => {
-                write!(f, "RouterError, caused by internal utf8 decode error: {}", e)?;
+            Self::Metrics(e) => {
+                write!(f, "RouterError, caused by internal prometheus error: {}", e)?;
+            }
+
+            Self::NoAvailableWorker(p) => {
+                write!(
+                    f,
+                    "RouterError, every worker running {} is unhealthy, unschedulable or cut off by an open circuit breaker",
+                    p
+                )?;
             }
 
             Self::PathNotFound(p) => {
                 write!(f, "RouterError, invalid path: {}", p)?;
             }
+
+            Self::RequestBodyTooLarge => {
+                write!(f, "RouterError, request body exceeds the router's size limit")?;
+            }
+
+            Self::ResponseBodyTooLarge => {
+                write!(
+                    f,
+                    "RouterError, worker response body exceeds the router's size limit"
+                )?;
+            }
+
+            Self::StalePushEvent(msg) => {
+                write!(f, "RouterError, out of order component event push: {}", msg)?;
+            }
+
+            Self::Timer(e) => {
+                write!(f, "RouterError, caused by internal tokio timer error: {}", e)?;
+            }
+
+            Self::Unauthorized => {
+                write!(f, "RouterError, missing or wrong push token")?;
+            }
+
+            Self::WorkerTimeout => {
+                write!(f, "RouterError, timed out waiting for a worker")?;
+            }
         }
         Ok(())
     }
@@ -45,6 +143,12 @@ impl From<hyper::error::Error> for RouterError {
     }
 }
 
+impl From<prometheus::Error> for RouterError {
+    fn from(e: prometheus::Error) -> Self {
+        Self::Metrics(e)
+    }
+}
+
 impl From<reqwest::Error> for RouterError {
     fn from(e: reqwest::Error) -> Self {
         Self::InvalidRequest(e)
@@ -57,23 +161,45 @@ impl From<serde_json::Error> for RouterError {
     }
 }
 
-impl From<Utf8Error> for RouterError {
-    fn from(e: Utf8Error) -> Self {
-        Self::InvalidUtf8(e)
+impl From<toml::de::Error> for RouterError {
+    fn from(e: toml::de::Error) -> Self {
+        Self::ConfigParse(e)
     }
 }
 
-impl Into<Response<Body>> for RouterError {
-    fn into(self) -> Response<Body> {
-        let msg = self.to_string();
-        let error_code = match self {
+impl From<timeout::Error<RouterError>> for RouterError {
+    fn from(e: timeout::Error<RouterError>) -> Self {
+        if e.is_elapsed() {
+            Self::WorkerTimeout
+        } else if e.is_timer() {
+            Self::Timer(e.into_timer().unwrap())
+        } else {
+            e.into_inner().unwrap()
+        }
+    }
+}
+
+impl RouterError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::InvalidAdminRequest(_) | Self::InvalidPushEvent(_) => 400,
+            Self::Unauthorized => 401,
             Self::PathNotFound(_) => 404,
+            Self::StalePushEvent(_) => 409,
+            Self::RequestBodyTooLarge => 413,
+            Self::ActivationFailed(_) => 502,
+            Self::NoAvailableWorker(_) | Self::ColdStartQueueFull(_) => 503,
+            Self::ColdStartTimeout(_) => 504,
             _ => 532,
-        };
+        }
+    }
+}
 
+impl Into<Response<Body>> for RouterError {
+    fn into(self) -> Response<Body> {
         Response::builder()
-            .status(error_code)
-            .body(Body::from(msg))
+            .status(self.status_code())
+            .body(Body::from(self.to_string()))
             .unwrap()
     }
 }
@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+// These only make sense for a single connection, so a proxy must not pass them along (RFC 7230, section 6.1)
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+// Pins a request to one version (`ComponentId::hash`) of its component, the same as `/sl/user/repo@hash/method`
+pub const X_V9_COMPONENT_HASH: &str = "x-v9-component-hash";
+// The same as `X_V9_COMPONENT_HASH`, for browsers (e.g. QA forcing a canary version)
+pub const V9_COMPONENT_HASH_COOKIE: &str = "v9_component_hash";
+
+// Turns the headers a client sent us into the headers we send the worker
+pub fn prepare_request_headers(headers: &mut HeaderMap, client_addr: SocketAddr) {
+    strip_hop_by_hop_headers(headers);
+
+    // We might be behind another proxy (e.g. NGINX doing TLS), so we extend its X-Forwarded-* headers rather than replace them
+    let forwarded_for = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, client_addr.ip()),
+        None => client_addr.ip().to_string(),
+    };
+    if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, forwarded_for);
+    }
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        // The router itself only ever speaks plain HTTP
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+
+    if !headers.contains_key(X_FORWARDED_HOST) {
+        if let Some(host) = headers.get(header::HOST).cloned() {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+    }
+
+    // The HTTP client fills this in for the worker connection
+    headers.remove(header::HOST);
+}
+
+// The value of the named cookie, if the client sent it
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| {
+            let mut parts = cookie.trim().splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, cookie_value)| cookie_value.to_string())
+}
+
+// Turns the headers a worker sent us into the headers we send the client
+pub fn prepare_response_headers(headers: &mut HeaderMap) {
+    strip_hop_by_hop_headers(headers);
+}
+
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // The Connection header can name extra headers that are only meant for this hop
+    let connection_headers: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in connection_headers {
+        headers.remove(name);
+    }
+
+    for name in &HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
@@ -0,0 +1,49 @@
+// The router itself, as a library so the benches can drive the real code (`main.rs` is the binary)
+//
+// I'd like the most pedantic warning level
+//
+#![warn(
+    clippy::cargo,
+    clippy::needless_borrow,
+    clippy::pedantic,
+    clippy::redundant_clone
+)]
+// But I don't care about these ones
+#![allow(
+    clippy::cast_precision_loss,     // There is no way to avoid this precision loss
+    clippy::missing_errors_doc,      // The library only exists so the router binary and the benches share code,
+    clippy::must_use_candidate,      // it isn't an API anyone else builds on
+    clippy::module_name_repetitions, // Sometimes clear naming calls for repetition
+    clippy::multiple_crate_versions,  // There is no way to easily fix this without modifying our dependencies 
+)]
+
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate prometheus;
+#[macro_use]
+extern crate serde;
+
+mod activation;
+pub mod admin;
+pub mod balancing;
+mod body;
+mod circuit_breaker;
+mod cold_start_queue;
+pub mod config;
+pub mod error;
+mod headers;
+pub mod load_balancer;
+mod metrics;
+pub mod model;
+mod push;
+pub mod reaper;
+mod request_forwarder;
+pub mod request_handler;
+mod retry;
+pub mod server;
+mod worker;
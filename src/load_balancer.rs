@@ -1,28 +1,131 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
+use std::hash::{Hash, Hasher};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use parking_lot::RwLock;
+use arc_swap::ArcSwap;
+use futures::future::{self, Either, Shared};
+use futures::sync::oneshot;
+use hyper::rt::{self, Future, Stream};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use rand::Rng;
+use reqwest::r#async::Client;
+use tokio::timer::Interval;
 
+use crate::balancing::{self, Affinity, BalancingStrategy, Candidate, ConsistentHash, SelectionContext};
+use crate::cold_start_queue::ColdStartQueue;
+use crate::config::{
+    CircuitBreakerConfig, HealthConfig, RouterConfig, StaleDataMode, StickyConfig, TrafficSplit,
+};
 use crate::error::RouterError;
-use crate::model::ComponentPath;
-use crate::worker::WorkerNode;
-
-// This is sensitive to how quickly the deployment manager makes changes
-const MAP_UPDATE_DELAY: Duration = Duration::from_secs(5);
+use crate::metrics;
+use crate::model::{ComponentEvent, ComponentEventAction, ComponentId, ComponentPath};
+use crate::worker::{CircuitPermit, WorkerNode};
 
 #[derive(Debug)]
 pub struct WorkerLoadBalancer {
-    workers: Vec<Arc<WorkerNode>>,
-    component_map: RwLock<ComponentMap>,
+    default_strategy: Box<dyn BalancingStrategy>,
+    strategy_overrides: HashMap<ComponentPath, Box<dyn BalancingStrategy>>,
+    sticky: HashMap<ComponentPath, StickyConfig>,
+    // Starts out as configured, but can be changed through the admin API
+    splits: RwLock<HashMap<ComponentPath, TrafficSplit>>,
+    // Only there if requests for unknown components should wait for them
+    cold_start_queue: Option<ColdStartQueue>,
+    // Activations waiting for their component to show up in a published map (see `wait_for_publish`)
+    component_waiters: Mutex<HashMap<ComponentPath, Vec<oneshot::Sender<()>>>>,
+    // This can be swapped out at runtime, requests already holding a removed worker just finish with it
+    workers: RwLock<Vec<Arc<WorkerNode>>>,
+    // Kept around so we can build nodes for workers added after startup
+    client: Client,
+    worker_status_timeout: Duration,
+    worker_health: HealthConfig,
+    circuit_breaker: CircuitBreakerConfig,
+    refresh_interval: Duration,
+    worker_grace_period: Duration,
+    // A new version is only preferred once it's up on this many available workers
+    min_workers_for_new_version: usize,
+    stale_data: StaleDataMode,
+    stale_refresh_min_interval: Duration,
+    // Readers just grab whatever snapshot is current, writers build a new one and swap it in
+    component_map: ArcSwap<ComponentMap>,
+    // Writers take turns, so a pushed change can't be lost to a refresh publishing at the same moment
+    component_map_writer: Mutex<MapWriter>,
+    refresh: CoalescedRefresh,
+    // Hashes don't say which version is newer, so without a generation to go by we use the order they showed up in
+    version_history: Mutex<VersionHistory>,
 }
 
+// How long a version that's gone from every worker is remembered, so one that drops out of a poll or two (e.g. its
+// only worker was briefly unreachable) keeps its place instead of coming back as the newest
+const FORGET_VERSIONS_AFTER: Duration = Duration::from_hours(1);
+
+// Every version we've seen, numbered by the component map update (refresh or push) each first showed up in
+// (so versions that showed up together, e.g. in the first refresh after a restart, rank the same)
 #[derive(Debug, Default)]
+struct VersionHistory {
+    updates: u64,
+    versions: HashMap<ComponentId, SeenVersion>,
+}
+
+#[derive(Debug)]
+struct SeenVersion {
+    first_update: u64,
+    // The highest anyone has reported for it
+    generation: Option<u64>,
+    last_seen: Instant,
+}
+
+// Published snapshots are never changed, an update clones the map and swaps the copy in
+#[derive(Clone, Debug, Default)]
 struct ComponentMap {
     seq_num: u64,
-    map: HashMap<ComponentPath, LoadBalancingData>,
+    map: HashMap<ComponentPath, ComponentVersions>,
+}
+
+// Every deployed version (`ComponentId::hash`) of one component, newest first
+#[derive(Clone, Debug, Default)]
+struct ComponentVersions {
+    versions: Vec<ComponentVersion>,
+}
+
+#[derive(Clone, Debug)]
+struct ComponentVersion {
+    hash: String,
+    first_update: u64,
+    generation: Option<u64>,
+    balancing_data: LoadBalancingData,
+}
+
+// The component map as the admin API shows it (component -> versions, newest first -> worker URLs)
+#[derive(Debug, Serialize)]
+pub struct ComponentMapSnapshot {
+    seq_num: u64,
+    components: BTreeMap<String, Vec<VersionSnapshot>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionSnapshot {
+    hash: String,
+    workers: Vec<String>,
+}
+
+// One version of a component and the workers running it
+#[derive(Debug)]
+pub struct VersionWorkers {
+    pub hash: String,
+    pub workers: Vec<Arc<WorkerNode>>,
+}
+
+// The worker picked for a request, and which version of the component it was picked for
+#[derive(Debug)]
+pub struct SelectedWorker {
+    pub worker: Arc<WorkerNode>,
+    pub version: String,
+    // Taken by the try that sends the request
+    pub circuit_permit: Option<CircuitPermit>,
 }
 
 #[derive(Debug, Default)]
@@ -31,88 +134,856 @@ struct LoadBalancingData {
     workers: Vec<Arc<WorkerNode>>,
 }
 
-impl WorkerLoadBalancer {
-    pub fn new(workers: Vec<WorkerNode>) -> Arc<WorkerLoadBalancer> {
-        let load_balancer = Arc::new(WorkerLoadBalancer {
-            workers: workers.into_iter().map(Arc::new).collect(),
-            component_map: RwLock::new(ComponentMap::default()),
+// Note: The copy carries on counting from wherever the original had got to
+impl Clone for LoadBalancingData {
+    fn clone(&self) -> Self {
+        Self {
+            counter: AtomicUsize::new(self.counter.load(Ordering::SeqCst)),
+            workers: self.workers.clone(),
+        }
+    }
+}
+
+// What was done about a request finding a worker that didn't have the component after all
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StaleDataAction {
+    // Started a refresh
+    Refreshed,
+    // Waited on the refresh that was already underway
+    Joined,
+    // A refresh started recently enough, so nothing
+    Skipped,
+    // Took the worker out of that version of the component
+    Evicted,
+}
+
+// Whoever holds this (locked) is the only one changing the component map
+#[derive(Debug, Default)]
+struct MapWriter {
+    // The components pushes (and evictions) have changed since the refresh that's polling started, if one is
+    changed_during_refresh: Option<HashSet<ComponentPath>>,
+}
+
+impl MapWriter {
+    fn changed(&mut self, path: &ComponentPath) {
+        if let Some(changed_during_refresh) = &mut self.changed_during_refresh {
+            changed_during_refresh.insert(path.clone());
+        }
+    }
+}
+
+type PendingRefresh = Shared<Box<dyn Future<Item = (), Error = ()> + Send>>;
+
+// The component map refresh that's underway, if there is one (anyone asking for a refresh meanwhile just waits on it)
+#[derive(Default)]
+struct CoalescedRefresh {
+    in_flight: Mutex<Option<PendingRefresh>>,
+    last_started: Mutex<Option<Instant>>,
+}
+
+// Note: The refresh itself is a future, so all we can show is whether there is one
+impl Debug for CoalescedRefresh {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("CoalescedRefresh")
+            .field("in_flight", &self.in_flight.lock().is_some())
+            .field("last_started", &self.last_started.lock())
+            .finish()
+    }
+}
+
+impl ComponentMap {
+    // Empty versions (and components with no versions left) are dropped
+    fn remove_worker(&mut self, id: &ComponentId, worker: &Arc<WorkerNode>) {
+        if let Some(component_versions) = self.map.get_mut(&id.path) {
+            for version in component_versions
+                .versions
+                .iter_mut()
+                .filter(|version| version.hash == id.hash)
+            {
+                version
+                    .balancing_data
+                    .workers
+                    .retain(|existing| !Arc::ptr_eq(existing, worker));
+            }
+            component_versions
+                .versions
+                .retain(|version| !version.balancing_data.workers.is_empty());
+        }
+        self.map
+            .retain(|_, component_versions| !component_versions.versions.is_empty());
+    }
+}
+
+impl VersionHistory {
+    // Numbers the next component map update
+    fn start_update(&mut self) -> u64 {
+        self.updates += 1;
+        self.updates
+    }
+
+    // Notes that `id` is in `update`, returning which update it first showed up in and its generation (if known)
+    fn saw(&mut self, id: &ComponentId, update: u64, generation: Option<u64>) -> (u64, Option<u64>) {
+        let seen = self.versions.entry(id.clone()).or_insert_with(|| SeenVersion {
+            first_update: update,
+            generation: None,
+            last_seen: Instant::now(),
+        });
+        seen.generation = seen.generation.max(generation);
+        seen.last_seen = Instant::now();
+
+        (seen.first_update, seen.generation)
+    }
+
+    fn forget_old_versions(&mut self) {
+        self.versions.retain(|id, seen| {
+            let keep = seen.last_seen.elapsed() < FORGET_VERSIONS_AFTER;
+            if !keep {
+                metrics::forget_version(id);
+            }
+            keep
         });
+    }
+}
+
+impl ComponentVersion {
+    fn available_workers(&self) -> usize {
+        self.balancing_data
+            .workers
+            .iter()
+            .filter(|worker| worker.is_available())
+            .count()
+    }
+}
+
+impl WorkerLoadBalancer {
+    pub fn new(client: Client, config: &RouterConfig) -> Arc<WorkerLoadBalancer> {
+        let load_balancer = WorkerLoadBalancer {
+            default_strategy: balancing::strategy_for(config.load_balancer.mode, &config.load_balancer),
+            strategy_overrides: config
+                .load_balancer
+                .component_overrides()
+                .into_iter()
+                .map(|(path, mode)| (path, balancing::strategy_for(mode, &config.load_balancer)))
+                .collect(),
+            sticky: config.load_balancer.component_sticky().into_iter().collect(),
+            splits: RwLock::new(config.load_balancer.component_splits().into_iter().collect()),
+            cold_start_queue: if config.cold_start_queue.enabled {
+                Some(ColdStartQueue::new(&config.cold_start_queue))
+            } else {
+                None
+            },
+            component_waiters: Mutex::new(HashMap::new()),
+            workers: RwLock::new(Vec::new()),
+            client,
+            worker_status_timeout: config.timeouts.worker_status(),
+            worker_health: config.health.clone(),
+            circuit_breaker: config.circuit_breaker.clone(),
+            refresh_interval: config.load_balancer.refresh_interval(),
+            worker_grace_period: config.load_balancer.worker_grace_period(),
+            min_workers_for_new_version: config.load_balancer.min_workers_for_new_version,
+            stale_data: config.load_balancer.stale_data,
+            stale_refresh_min_interval: config.load_balancer.stale_refresh_min_interval(),
+            component_map: ArcSwap::from_pointee(ComponentMap::default()),
+            component_map_writer: Mutex::new(MapWriter::default()),
+            refresh: CoalescedRefresh::default(),
+            version_history: Mutex::new(VersionHistory::default()),
+        };
+        load_balancer.set_workers(&config.workers);
 
-        if let Err(e) = load_balancer.update_component_map() {
-            warn!("Initial load balancer update failed: {}", e);
+        Arc::new(load_balancer)
+    }
+
+    pub fn workers(&self) -> Vec<Arc<WorkerNode>> {
+        self.workers.read().clone()
+    }
+
+    pub fn find_worker(&self, worker_url: &str) -> Option<Arc<WorkerNode>> {
+        self.workers
+            .read()
+            .iter()
+            .find(|worker| worker.request_url() == worker_url)
+            .cloned()
+    }
+
+    pub fn worker_urls(&self) -> Vec<String> {
+        worker_urls(&self.workers.read())
+    }
+
+    // Replaces the worker set, keeping the existing node (and its refresh state) for any worker that stays
+    // Note: This doesn't touch the component map, the next `update_component_map` picks up the new set
+    pub fn set_workers(&self, worker_urls: &[String]) {
+        let mut workers = self.workers.write();
+
+        let mut new_workers: Vec<Arc<WorkerNode>> = Vec::with_capacity(worker_urls.len());
+        for worker_url in worker_urls {
+            if new_workers
+                .iter()
+                .any(|worker| worker.request_url() == worker_url)
+            {
+                warn!("Ignoring duplicate worker {}", worker_url);
+                continue;
+            }
+
+            if let Some(existing_worker) =
+                workers.iter().find(|worker| worker.request_url() == worker_url)
+            {
+                new_workers.push(existing_worker.clone());
+            } else {
+                info!("Adding worker {}", worker_url);
+                new_workers.push(Arc::new(WorkerNode::new(
+                    worker_url.clone(),
+                    self.client.clone(),
+                    self.worker_status_timeout,
+                    self.worker_health.clone(),
+                    self.circuit_breaker.clone(),
+                )));
+            }
         }
 
-        // This is the background updater thread
-        let background_handle = Arc::downgrade(&load_balancer);
-        thread::spawn(move || {
-            while let Some(load_balancer) = background_handle.upgrade() {
-                thread::sleep(MAP_UPDATE_DELAY);
+        for removed_worker in workers
+            .iter()
+            .filter(|worker| !worker_urls.iter().any(|url| url == worker.request_url()))
+        {
+            info!("Removing worker {}", removed_worker.request_url());
+            metrics::forget_worker(removed_worker.request_url());
+            removed_worker.forget_circuit_breaker_metrics();
+        }
+
+        *workers = new_workers;
+    }
+
+    // This is the background updater task, it needs to be spawned onto the runtime
+    // (the first update happens immediately, then once every `refresh_interval`)
+    pub fn background_updater(self: Arc<Self>) -> impl Future<Item = (), Error = ()> + Send {
+        let background_handle = Arc::downgrade(&self);
+
+        Interval::new(Instant::now(), self.refresh_interval)
+            .map_err(|e| error!("Load balancer update timer failed: {}", e))
+            .for_each(move |_| match background_handle.upgrade() {
+                Some(load_balancer) => Either::A(load_balancer.update_component_map()),
+                // The load balancer is gone, so there is nothing left to update
+                None => Either::B(future::err(())),
+            })
+    }
+
+    // Only one refresh runs at a time, asking for one while it's underway just waits for it to finish
+    pub fn update_component_map(self: Arc<Self>) -> impl Future<Item = (), Error = ()> + Send {
+        let refresh = self
+            .refresh
+            .in_flight
+            .lock()
+            .get_or_insert_with(|| self.clone().start_refresh().shared())
+            .clone();
+
+        refresh.map(|_| ()).map_err(|_| ())
+    }
+
+    fn start_refresh(self: Arc<Self>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        *self.refresh.last_started.lock() = Some(Instant::now());
+
+        Box::new(self.clone().refresh_component_map().then(move |result| {
+            *self.refresh.in_flight.lock() = None;
+            result
+        }))
+    }
+
+    fn refresh_component_map(self: Arc<Self>) -> impl Future<Item = (), Error = ()> + Send {
+        // Changes pushed while we're polling are newer than anything we're about to hear about those components
+        self.component_map_writer.lock().changed_during_refresh = Some(HashSet::new());
 
-                if let Err(e) = load_balancer.update_component_map() {
-                    warn!("Load balancer update failed: {}", e);
+        // Each worker is polled on its own (and concurrently), so one unreachable worker can't freeze routing for the others
+        let worker_refreshes: Vec<_> = self
+            .workers
+            .read()
+            .iter()
+            .map(|worker| {
+                let worker = worker.clone();
+                worker
+                    .clone()
+                    .refresh_component_list(self.worker_grace_period)
+                    .map(move |components_on_worker| (worker, components_on_worker))
+            })
+            .collect();
+
+        future::join_all(worker_refreshes).map(move |worker_components| {
+            // Create a new map to replace the old one
+            let mut new_map: HashMap<ComponentId, LoadBalancingData> = HashMap::new();
+            for (worker, components_on_worker) in worker_components {
+                for component in components_on_worker {
+                    let map_entry = new_map.entry(component);
+                    let balancing_data = map_entry.or_default();
+                    balancing_data.workers.push(worker.clone());
                 }
             }
-        });
+            let mut new_map = self.group_versions(new_map);
+
+            let mut writer = self.component_map_writer.lock();
+            let current_map = self.component_map.load();
 
-        load_balancer
+            // So a push that landed while we were polling wins for the components it changed (the next refresh will
+            // have caught up with it), everything else is as polled
+            for path in writer.changed_during_refresh.take().unwrap_or_default() {
+                match current_map.map.get(&path) {
+                    Some(component_versions) => new_map.insert(path, component_versions.clone()),
+                    None => new_map.remove(&path),
+                };
+            }
+            self.publish(
+                &writer,
+                ComponentMap {
+                    seq_num: current_map.seq_num,
+                    map: new_map,
+                },
+            );
+        })
     }
 
-    fn update_component_map(&self) -> Result<(), RouterError> {
-        // We measure a `seq_num` so we don't f****** smoke someone else's update
-        let seq_num = self.component_map.read().seq_num;
+    // Applies pushed changes straight to the component map (all of them, or none if any names a worker we don't have),
+    // returning the map's new `seq_num`
+    pub fn apply_component_events(&self, events: &[ComponentEvent]) -> Result<u64, RouterError> {
+        let workers = events
+            .iter()
+            .map(|event| {
+                self.find_worker(&event.worker).ok_or_else(|| {
+                    RouterError::InvalidPushEvent(format!("no such worker: {}", event.worker))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut writer = self.component_map_writer.lock();
+        let mut component_map = ComponentMap::clone(&self.component_map.load());
+        let mut version_history = self.version_history.lock();
+        let update = version_history.start_update();
+        for (event, worker) in events.iter().zip(workers) {
+            let id = &event.component;
+            info!(
+                "Pushed event: {:?} {}@{} on {}",
+                event.action, id.path, id.hash, event.worker
+            );
+            writer.changed(&id.path);
 
-        // Create a new map to replace the old one
-        let mut new_map: HashMap<ComponentPath, LoadBalancingData> = HashMap::new();
-        for worker in &self.workers {
-            // TODO: Add partial updates if one of these requests fail
-            let components_on_worker = worker.get_component_list()?;
+            match event.action {
+                ComponentEventAction::Add => {
+                    let (first_update, generation) = version_history.saw(id, update, event.generation);
+                    let versions = &mut component_map.map.entry(id.path.clone()).or_default().versions;
 
-            for component in components_on_worker {
-                let map_entry = new_map.entry(component);
-                let balancing_data = map_entry.or_default();
-                balancing_data.workers.push(worker.clone());
+                    if let Some(version) = versions.iter_mut().find(|version| version.hash == id.hash) {
+                        version.generation = generation;
+                        let workers = &mut version.balancing_data.workers;
+                        if !workers.iter().any(|existing| Arc::ptr_eq(existing, &worker)) {
+                            workers.push(worker);
+                        }
+                    } else {
+                        versions.push(ComponentVersion {
+                            hash: id.hash.clone(),
+                            first_update,
+                            generation,
+                            balancing_data: LoadBalancingData {
+                                counter: AtomicUsize::new(0),
+                                workers: vec![worker],
+                            },
+                        });
+                    }
+                    // A new worker or generation can change which version counts as the newest
+                    sort_versions(versions);
+                }
+                ComponentEventAction::Remove => component_map.remove_worker(id, &worker),
             }
         }
 
-        let mut component_map = self.component_map.write();
+        Ok(self.publish(&writer, component_map))
+    }
+
+    // Takes one worker out of one version of a component, leaving the rest of the map alone
+    // (requests that found the same stale worker at the same time only take it out once)
+    fn evict_worker(&self, id: &ComponentId, worker: &Arc<WorkerNode>) {
+        let mut writer = self.component_map_writer.lock();
+
+        let current_map = self.component_map.load();
+        let is_listed = current_map
+            .map
+            .get(&id.path)
+            .and_then(|component_versions| {
+                component_versions
+                    .versions
+                    .iter()
+                    .find(|version| version.hash == id.hash)
+            })
+            .is_some_and(|version| {
+                version
+                    .balancing_data
+                    .workers
+                    .iter()
+                    .any(|existing| Arc::ptr_eq(existing, worker))
+            });
+        if !is_listed {
+            return;
+        }
+
+        info!(
+            "Evicting {}@{} on {}, the worker doesn't have it",
+            id.path,
+            id.hash,
+            worker.request_url()
+        );
+        metrics::STALE_DATA_ACTIONS
+            .with_label_values(&[stale_data_action_label(StaleDataAction::Evicted)])
+            .inc();
 
-        // If the sequence numbers don't match up than we lost an update race, we should forget about updating the table
-        // (they did the work at the same time nessesarily, so our data is not "more fresh")
+        writer.changed(&id.path);
+        let mut component_map = ComponentMap::clone(&current_map);
+        component_map.remove_worker(id, worker);
+        self.publish(&writer, component_map);
+    }
+
+    // Swaps in a new component map, returning its `seq_num` (only whoever holds `component_map_writer` can publish)
+    fn publish(&self, _writer: &MutexGuard<'_, MapWriter>, mut component_map: ComponentMap) -> u64 {
+        component_map.seq_num += 1;
+        let seq_num = component_map.seq_num;
+
+        let component_map = Arc::new(component_map);
+        self.component_map.store(component_map.clone());
+        // Note: Only after the store, so a request `ColdStartQueue::wait_for` parks has either seen this map or gets woken
+        self.wake_cold_start_queue(&component_map);
+
+        seq_num
+    }
+
+    fn wake_cold_start_queue(&self, component_map: &ComponentMap) {
+        if let Some(cold_start_queue) = &self.cold_start_queue {
+            cold_start_queue.wake_ready(|path| component_map.map.contains_key(path));
+        }
+
+        self.component_waiters.lock().retain(|path, senders| {
+            if !component_map.map.contains_key(path) {
+                return true;
+            }
+
+            for sender in senders.drain(..) {
+                let _ = sender.send(());
+            }
+            false
+        });
+    }
 
-        // So only update if they do match
-        if component_map.seq_num == seq_num {
-            component_map.seq_num += 1;
-            component_map.map = new_map
+    // Resolves once a component map with `path` in it is published (by a refresh or a push), or straight away if the
+    // current one has it
+    // Note: Checked with the waiters locked, for the same reason as in `ColdStartQueue::wait_for`
+    pub fn wait_for_publish(&self, path: &ComponentPath) -> impl Future<Item = (), Error = ()> + Send {
+        let mut waiters = self.component_waiters.lock();
+        if self.has_component(path) {
+            return Either::A(future::ok(()));
         }
 
-        Ok(())
+        // Waiters that gave up (they timed out, or went round again) don't stick around
+        for senders in waiters.values_mut() {
+            senders.retain(|sender| !sender.is_canceled());
+        }
+        waiters.retain(|_, senders| !senders.is_empty());
+
+        let (sender, receiver) = oneshot::channel();
+        waiters.entry(path.clone()).or_default().push(sender);
+        Either::B(receiver.map_err(|_| ()))
     }
 
-    pub fn get_worker_found_stale_data(
+    // Groups each component's versions together, ordered newest first
+    fn group_versions(
         &self,
+        version_map: HashMap<ComponentId, LoadBalancingData>,
+    ) -> HashMap<ComponentPath, ComponentVersions> {
+        let mut version_history = self.version_history.lock();
+        let update = version_history.start_update();
+
+        let mut grouped_map: HashMap<ComponentPath, ComponentVersions> = HashMap::new();
+        for (id, balancing_data) in version_map {
+            let reported_generation = balancing_data
+                .workers
+                .iter()
+                .filter_map(|worker| worker.component_generation(&id))
+                .max();
+            let (first_update, generation) = version_history.saw(&id, update, reported_generation);
+
+            grouped_map
+                .entry(id.path)
+                .or_default()
+                .versions
+                .push(ComponentVersion {
+                    hash: id.hash,
+                    first_update,
+                    generation,
+                    balancing_data,
+                });
+        }
+        // Versions that are gone for good shouldn't be remembered forever
+        version_history.forget_old_versions();
+
+        for component_versions in grouped_map.values_mut() {
+            sort_versions(&mut component_versions.versions);
+        }
+
+        grouped_map
+    }
+
+    // The worker we picked didn't have the component after all, so we fix the component map up (refreshing it, or just
+    // evicting the worker) before the request is tried somewhere else
+    pub fn found_stale_data(
+        self: Arc<Self>,
         path: &ComponentPath,
-    ) -> Result<Option<Arc<WorkerNode>>, RouterError> {
-        self.update_component_map()?;
+        stale: &SelectedWorker,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        match self.stale_data {
+            StaleDataMode::Refresh => Either::A(self.refresh_for_stale_data()),
+            StaleDataMode::Evict => {
+                let id = ComponentId {
+                    path: path.clone(),
+                    hash: stale.version.clone(),
+                };
+                self.evict_worker(&id, &stale.worker);
+                Either::B(future::ok(()))
+            }
+        }
+    }
 
-        Ok(self.get_worker(path))
+    // A burst of requests finding the same stale data shares one refresh, and a refresh that started less than
+    // `stale_refresh_min_interval` ago is taken as fresh enough
+    fn refresh_for_stale_data(self: Arc<Self>) -> impl Future<Item = (), Error = ()> + Send {
+        let action = self.limited_refresh_action();
+        metrics::STALE_DATA_ACTIONS
+            .with_label_values(&[stale_data_action_label(action)])
+            .inc();
+
+        if action == StaleDataAction::Skipped {
+            Either::A(future::ok(()))
+        } else {
+            Either::B(self.update_component_map())
+        }
     }
 
-    pub fn get_worker(&self, path: &ComponentPath) -> Option<Arc<WorkerNode>> {
-        let component_map = self.component_map.read();
+    // Starts a refresh in the background, under the same limit as refreshes for stale data (so however many callers
+    // keep asking, the workers aren't polled more than once every `stale_refresh_min_interval`)
+    pub fn request_refresh(self: Arc<Self>) {
+        if self.limited_refresh_action() == StaleDataAction::Refreshed {
+            rt::spawn(self.update_component_map());
+        }
+    }
+
+    fn limited_refresh_action(&self) -> StaleDataAction {
+        if self.refresh.in_flight.lock().is_some() {
+            StaleDataAction::Joined
+        } else if self
+            .refresh
+            .last_started
+            .lock()
+            .is_some_and(|started| started.elapsed() < self.stale_refresh_min_interval)
+        {
+            StaleDataAction::Skipped
+        } else {
+            StaleDataAction::Refreshed
+        }
+    }
+
+    pub fn sticky_config(&self, path: &ComponentPath) -> Option<&StickyConfig> {
+        self.sticky.get(path)
+    }
+
+    pub fn cold_start_queue(&self) -> Option<&ColdStartQueue> {
+        self.cold_start_queue.as_ref()
+    }
+
+    pub fn has_component(&self, path: &ComponentPath) -> bool {
+        self.component_map.load().map.contains_key(path)
+    }
 
-        // We update every 5 seconds, and literally missing data should only happen in a few cases
+    // Whether any version of the component has been in a refresh or a push in the last `FORGET_VERSIONS_AFTER`
+    // (even if it's gone from every worker since)
+    pub fn recently_seen(&self, path: &ComponentPath) -> bool {
+        self.version_history
+            .lock()
+            .versions
+            .keys()
+            .any(|id| id.path == *path)
+    }
+
+    pub fn get_worker(
+        &self,
+        path: &ComponentPath,
+        version: Option<&str>,
+        affinity: &Affinity,
+    ) -> Result<SelectedWorker, RouterError> {
+        self.get_worker_excluding(path, version, affinity, &[])
+    }
+
+    // Retries go to a different replica, so the workers already tried are left out
+    pub fn get_worker_excluding(
+        &self,
+        path: &ComponentPath,
+        version: Option<&str>,
+        affinity: &Affinity,
+        excluded: &[Arc<WorkerNode>],
+    ) -> Result<SelectedWorker, RouterError> {
+        let component_map = self.component_map.load();
+
+        // We update every `refresh_interval` (5 seconds by default), and literally missing data should only happen in a few cases
         // 1) Initial deployment hasn't finished yet (nothing to be done)
-        // 2) Initial deployment is done but we haven't picked it up yet (simply a five second delay to the user)
+        // 2) Initial deployment is done but we haven't picked it up yet (simply a `refresh_interval` delay to the user)
         // 3) There is no instance up due to bad deployment manager code (this is a DM bug)
         // 4) A bug somewhere else (nothing to be done)
         // None of these cases is worth a retry
-        component_map.map.get(path).and_then(|load_balancing_data| {
-            let idx = load_balancing_data.counter.fetch_add(1, Ordering::SeqCst);
-            if load_balancing_data.workers.is_empty() {
-                None
-            } else {
-                Some(load_balancing_data.workers[idx % load_balancing_data.workers.len()].clone())
+        let component_versions = component_map
+            .map
+            .get(path)
+            .ok_or_else(|| RouterError::PathNotFound(format!("no such component: {}", path)))?;
+        let component_version = match version {
+            Some(_) => self.choose_version(path, component_versions, version)?,
+            None => match self.split_version(path, component_versions, affinity) {
+                Some(component_version) => component_version,
+                None => self.choose_version(path, component_versions, None)?,
+            },
+        };
+        let load_balancing_data = &component_version.balancing_data;
+
+        // Otherwise let the strategy pick one, out of the workers that are healthy and schedulable
+        let mut available_workers: Vec<&Arc<WorkerNode>> = load_balancing_data
+            .workers
+            .iter()
+            .filter(|worker| worker.is_available())
+            .filter(|worker| !excluded.iter().any(|excluded| Arc::ptr_eq(excluded, worker)))
+            .collect();
+        let mut candidates: Vec<Candidate<'_>> = available_workers
+            .iter()
+            .map(|worker| Candidate {
+                worker_url: worker.request_url(),
+                load: worker.load(),
+                latency: worker.component_latency(path),
+                in_flight: worker.component_in_flight_requests(path),
+            })
+            .collect();
+        let context = SelectionContext {
+            sequence: load_balancing_data.counter.fetch_add(1, Ordering::SeqCst),
+            affinity_key: Some(affinity.key()),
+        };
+
+        // Sticky requests are always hashed onto a worker, so when one leaves only the keys that were on it move
+        let strategy: &dyn BalancingStrategy = match affinity {
+            Affinity::Sticky(_) => &ConsistentHash,
+            Affinity::Client(_) => self
+                .strategy_overrides
+                .get(path)
+                .unwrap_or(&self.default_strategy)
+                .as_ref(),
+        };
+        // A worker cut off by an open circuit breaker (or a half open one with no trials left) is dropped and the
+        // strategy picks again, if that's all of them the request fails fast instead of waiting on a failing worker
+        while let Some(idx) = strategy.choose(&candidates, &context) {
+            if let Some(circuit_permit) = available_workers[idx].try_acquire_circuit(path) {
+                return Ok(SelectedWorker {
+                    worker: available_workers[idx].clone(),
+                    version: component_version.hash.clone(),
+                    circuit_permit: Some(circuit_permit),
+                });
             }
-        })
+            available_workers.remove(idx);
+            candidates.remove(idx);
+        }
+
+        Err(RouterError::NoAvailableWorker(path.to_string()))
+    }
+
+    // A pinned version is used as is, otherwise we go with the newest version that's up on enough workers
+    // (if none are, whichever is up on the most workers, ties going to the older version since it was there first)
+    fn choose_version<'a>(
+        &self,
+        path: &ComponentPath,
+        component_versions: &'a ComponentVersions,
+        pinned_version: Option<&str>,
+    ) -> Result<&'a ComponentVersion, RouterError> {
+        if let Some(pinned_version) = pinned_version {
+            return component_versions
+                .versions
+                .iter()
+                .find(|version| version.hash == pinned_version)
+                .ok_or_else(|| {
+                    RouterError::PathNotFound(format!(
+                        "no such component version: {}@{}",
+                        path, pinned_version
+                    ))
+                });
+        }
+
+        component_versions
+            .versions
+            .iter()
+            .find(|version| version.available_workers() >= self.min_workers_for_new_version)
+            .or_else(|| {
+                component_versions
+                    .versions
+                    .iter()
+                    .filter(|version| version.available_workers() > 0)
+                    .max_by_key(|version| version.available_workers())
+            })
+            .or_else(|| component_versions.versions.first())
+            .ok_or_else(|| RouterError::PathNotFound(format!("no such component: {}", path)))
+    }
+
+    // A weighted pick between the versions in the component's traffic split that are up on any available worker
+    // (sticky requests always get the same pick, so a user doesn't flip between versions), versions missing from
+    // the split get nothing, and if none of the split's versions are up we fall back to `choose_version`
+    fn split_version<'a>(
+        &self,
+        path: &ComponentPath,
+        component_versions: &'a ComponentVersions,
+        affinity: &Affinity,
+    ) -> Option<&'a ComponentVersion> {
+        let splits = self.splits.read();
+        let split = splits.get(path)?;
+
+        let weighted_versions: Vec<(&ComponentVersion, u64)> = component_versions
+            .versions
+            .iter()
+            .filter_map(|version| Some((version, u64::from(*split.get(&version.hash)?))))
+            .filter(|(version, weight)| *weight > 0 && version.available_workers() > 0)
+            .collect();
+        let total_weight: u64 = weighted_versions.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut target = match affinity {
+            Affinity::Sticky(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() % total_weight
+            }
+            Affinity::Client(_) => rand::thread_rng().gen_range(0, total_weight),
+        };
+        for (version, weight) in weighted_versions {
+            if target < weight {
+                return Some(version);
+            }
+            target -= weight;
+        }
+
+        None
+    }
+
+    // The version unpinned requests go to when there's no traffic split
+    pub fn preferred_version(&self, path: &ComponentPath) -> Option<String> {
+        let component_map = self.component_map.load();
+        let component_versions = component_map.map.get(path)?;
+        self.choose_version(path, component_versions, None)
+            .ok()
+            .map(|version| version.hash.clone())
     }
+
+    pub fn traffic_splits(&self) -> BTreeMap<String, TrafficSplit> {
+        self.splits
+            .read()
+            .iter()
+            .map(|(path, split)| (path.to_string(), split.clone()))
+            .collect()
+    }
+
+    pub fn traffic_split(&self, path: &ComponentPath) -> Option<TrafficSplit> {
+        self.splits.read().get(path).cloned()
+    }
+
+    pub fn set_traffic_split(&self, path: ComponentPath, split: TrafficSplit) {
+        info!("Splitting traffic for {} as {:?}", path, split);
+        self.splits.write().insert(path, split);
+    }
+
+    // Returns false if the component didn't have a split to remove
+    pub fn remove_traffic_split(&self, path: &ComponentPath) -> bool {
+        let removed = self.splits.write().remove(path).is_some();
+        if removed {
+            info!("Removed the traffic split for {}", path);
+        }
+        removed
+    }
+
+    pub fn component_snapshot(&self) -> ComponentMapSnapshot {
+        let component_map = self.component_map.load();
+
+        ComponentMapSnapshot {
+            seq_num: component_map.seq_num,
+            components: component_map
+                .map
+                .iter()
+                .map(|(path, component_versions)| {
+                    let versions = component_versions
+                        .versions
+                        .iter()
+                        .map(|version| VersionSnapshot {
+                            hash: version.hash.clone(),
+                            workers: worker_urls(&version.balancing_data.workers),
+                        })
+                        .collect();
+                    (path.to_string(), versions)
+                })
+                .collect(),
+        }
+    }
+
+    // Every version of every component, with the workers running it
+    pub fn component_replicas(&self) -> Vec<(ComponentPath, Vec<VersionWorkers>)> {
+        self.component_map
+            .load()
+            .map
+            .iter()
+            .map(|(path, component_versions)| (path.clone(), version_workers(component_versions)))
+            .collect()
+    }
+
+    pub fn component_versions(&self, path: &ComponentPath) -> Option<Vec<VersionWorkers>> {
+        self.component_map.load().map.get(path).map(version_workers)
+    }
+}
+
+// Newest first, going by generation if every version has one, otherwise by the update each first showed up in
+// Note: Versions that showed up together (e.g. the router restarted mid-rollout) go by how many workers they're on,
+// then by hash, so they come out in the same order every time
+fn sort_versions(versions: &mut [ComponentVersion]) {
+    let by_generation = versions.iter().all(|version| version.generation.is_some());
+    let generation = |version: &ComponentVersion| version.generation.filter(|_| by_generation);
+
+    versions.sort_by(|a, b| {
+        generation(b)
+            .cmp(&generation(a))
+            .then_with(|| b.first_update.cmp(&a.first_update))
+            .then_with(|| {
+                b.balancing_data
+                    .workers
+                    .len()
+                    .cmp(&a.balancing_data.workers.len())
+            })
+            .then_with(|| a.hash.cmp(&b.hash))
+    });
+}
+
+// Matches what the `v9_router_stale_data_actions_total` help text lists
+fn stale_data_action_label(action: StaleDataAction) -> &'static str {
+    match action {
+        StaleDataAction::Refreshed => "refreshed",
+        StaleDataAction::Joined => "joined",
+        StaleDataAction::Skipped => "skipped",
+        StaleDataAction::Evicted => "evicted",
+    }
+}
+
+fn worker_urls(workers: &[Arc<WorkerNode>]) -> Vec<String> {
+    workers
+        .iter()
+        .map(|worker| worker.request_url().to_string())
+        .collect()
+}
+
+fn version_workers(component_versions: &ComponentVersions) -> Vec<VersionWorkers> {
+    component_versions
+        .versions
+        .iter()
+        .map(|version| VersionWorkers {
+            hash: version.hash.clone(),
+            workers: version.balancing_data.workers.clone(),
+        })
+        .collect()
 }
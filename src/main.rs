@@ -13,42 +13,73 @@
     clippy::multiple_crate_versions,  // There is no way to easily fix this without modifying our dependencies 
 )]
 
-#[macro_use]
-extern crate failure;
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate serde;
-
-mod error;
-mod load_balancer;
-mod model;
-mod request_forwarder;
-mod request_handler;
-mod server;
-mod worker;
 
-use std::env;
+use std::process;
 use std::sync::Arc;
 
-use crate::request_handler::HttpRequestHandler;
+use v9_router::admin::{self, AdminHandler};
+use v9_router::config::RouterConfig;
+use v9_router::reaper;
+use v9_router::request_handler::{self, HttpRequestHandler};
+use v9_router::server::{self, BackgroundTask};
 
 fn main() {
-    let log_spec = "debug, hyper=info, mio=info, tokio_reactor=info, tokio_threadpool=info";
+    // The logger isn't up yet, so config problems go straight to stderr
+    let config = match RouterConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    flexi_logger::Logger::with_str(log_spec).start().unwrap();
+    flexi_logger::Logger::with_str(&config.logging.spec)
+        .start()
+        .unwrap();
     info!("Router started...(logger initialized)");
+    debug!("Using config {:?}", config);
 
-    let is_development_mode = env::args().any(|arg| arg == "--development");
-    if is_development_mode {
-        info!("Starting in development mode");
-    }
+    let http_request_handler = match HttpRequestHandler::new(&config) {
+        Ok(http_request_handler) => http_request_handler,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let admin_handler = Arc::new(AdminHandler::new(
+        http_request_handler.load_balancer(),
+        http_request_handler.activator(),
+    ));
 
-    let http_request_handler = HttpRequestHandler::new();
+    let mut background_tasks: Vec<BackgroundTask> = vec![
+        Box::new(http_request_handler.background_tasks()),
+        Box::new(admin::reload_on_sighup(admin_handler.clone())),
+    ];
+    if config.reaper.enabled {
+        info!(
+            "Deactivating replicas idle for over {}s (keeping at least {} per component)",
+            config.reaper.idle_ttl_secs, config.reaper.min_replicas
+        );
+        background_tasks.push(Box::new(reaper::idle_reaper(
+            http_request_handler.load_balancer(),
+            &config.reaper,
+        )));
+    }
+    if config.admin.enabled {
+        info!("Starting admin API on {}...", config.admin_address());
+        background_tasks.push(Box::new(server::serve(
+            config.admin_address(),
+            admin_handler,
+            admin::global_admin_entrypoint,
+        )));
+    }
 
     server::start_server(
-        is_development_mode,
+        config.bind_address(),
         Arc::new(http_request_handler),
         request_handler::global_request_entrypoint,
+        background_tasks,
     );
 }
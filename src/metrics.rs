@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::Mutex;
+use prometheus::{Encoder, GaugeVec, HistogramVec, IntCounterVec, TextEncoder};
+
+use crate::error::RouterError;
+use crate::model::{ComponentId, ComponentPath};
+
+// Past this many components, new ones are all counted under `OTHER_COMPONENT` (so a flood of deployments can't
+// blow up the number of time series we export)
+const MAX_COMPONENT_LABELS: usize = 1000;
+pub const OTHER_COMPONENT: &str = "other";
+// Requests that don't name a component we know about (typos, scanners, components that aren't up yet, etc.)
+pub const UNKNOWN_COMPONENT: &str = "unknown";
+// The component label of a breaker covering a whole worker, rather than one component on it
+pub const ALL_COMPONENTS: &str = "all";
+// Every deployment brings a new version, so a version's series are dropped once the load balancer forgets it (see
+// `forget_version`), and past this many versions of one component at once the rest are counted under `OTHER_VERSION`
+const MAX_VERSION_LABELS_PER_COMPONENT: usize = 10;
+pub const OTHER_VERSION: &str = "other";
+
+// What's been exported for one version, so all of its series can be dropped later
+#[derive(Debug)]
+struct VersionLabel {
+    component_label: String,
+    statuses: HashSet<u16>,
+}
+
+lazy_static! {
+    static ref COMPONENT_LABELS: Mutex<HashSet<ComponentPath>> = Mutex::new(HashSet::new());
+    static ref VERSION_LABELS: Mutex<HashMap<ComponentId, VersionLabel>> = Mutex::new(HashMap::new());
+
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_requests_total",
+        "Requests handled by the router, by component and response status",
+        &["component", "status"]
+    )
+    .unwrap();
+
+    // Note: This is the time until the response headers are ready, the body may still be streaming after that
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "v9_router_request_duration_seconds",
+        "Time from a request reaching the router to its response being ready, by component",
+        &["component"]
+    )
+    .unwrap();
+
+    // Note: Only requests that got as far as a worker are counted here, so versions can be compared on equal terms
+    pub static ref VERSION_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_version_requests_total",
+        "Requests forwarded to each version of a component, by component, version and response status",
+        &["component", "version", "status"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "v9_router_worker_request_duration_seconds",
+        "Time from forwarding a request to a worker until its response headers come back, by worker",
+        &["worker"]
+    )
+    .unwrap();
+
+    pub static ref STALE_DATA_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "v9_router_stale_data_retries_total",
+        "Requests that hit a worker no longer running their component, by component",
+        &["component"]
+    )
+    .unwrap();
+
+    pub static ref RETRIES: IntCounterVec = register_int_counter_vec!(
+        "v9_router_retries_total",
+        "Requests tried again on another worker, by component and why (connect_failure, timeout, unavailable or stale_data)",
+        &["component", "reason"]
+    )
+    .unwrap();
+
+    pub static ref STALE_DATA_ACTIONS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_stale_data_actions_total",
+        "What was done about stale component map data (refreshed, joined, skipped or evicted)",
+        &["action"]
+    )
+    .unwrap();
+
+    pub static ref COLD_START_QUEUE: IntCounterVec = register_int_counter_vec!(
+        "v9_router_cold_start_queue_total",
+        "Requests for not yet known components, by how their wait ended (ready, timeout or overflow)",
+        &["result"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_REFRESH_DURATION: HistogramVec = register_histogram_vec!(
+        "v9_router_worker_refresh_duration_seconds",
+        "Time taken to poll a worker's status, by worker",
+        &["worker"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_REFRESH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "v9_router_worker_refresh_failures_total",
+        "Failed worker status polls, by worker",
+        &["worker"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_HEALTHY: GaugeVec = register_gauge_vec!(
+        "v9_router_worker_healthy",
+        "Whether each worker is currently healthy (1) or ejected from selection (0)",
+        &["worker"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_EJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_worker_ejections_total",
+        "Times each worker has been ejected for failing too often, by worker",
+        &["worker"]
+    )
+    .unwrap();
+
+    pub static ref DEACTIVATIONS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_deactivations_total",
+        "Idle replicas the router asked workers to deactivate, by component and result",
+        &["component", "result"]
+    )
+    .unwrap();
+
+    pub static ref CIRCUIT_BREAKER_STATE: GaugeVec = register_gauge_vec!(
+        "v9_router_circuit_breaker_state",
+        "State of each circuit breaker (0 closed, 1 half open, 2 open), by worker and component (\"all\" for the whole worker)",
+        &["worker", "component"]
+    )
+    .unwrap();
+
+    pub static ref CIRCUIT_BREAKER_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "v9_router_circuit_breaker_transitions_total",
+        "Circuit breaker state changes, by worker, component and the state changed to (closed, open or half_open)",
+        &["worker", "component", "state"]
+    )
+    .unwrap();
+
+    pub static ref WORKER_COMPONENTS: GaugeVec = register_gauge_vec!(
+        "v9_router_worker_components",
+        "Components the router is routing to each worker",
+        &["worker"]
+    )
+    .unwrap();
+}
+
+// Only call this for components that are actually in the component map, anything else should be `UNKNOWN_COMPONENT`
+pub fn component_label(path: &ComponentPath) -> String {
+    let mut component_labels = COMPONENT_LABELS.lock();
+
+    if component_labels.contains(path) {
+        path.to_string()
+    } else if component_labels.len() < MAX_COMPONENT_LABELS {
+        component_labels.insert(path.clone());
+        path.to_string()
+    } else {
+        OTHER_COMPONENT.to_string()
+    }
+}
+
+// Counts a request forwarded to one version of a component, `component_label` being what `component_label` gave for it
+pub fn count_version_request(id: &ComponentId, component_label: &str, status: u16) {
+    let mut version_labels = VERSION_LABELS.lock();
+
+    let version = if component_label == OTHER_COMPONENT {
+        OTHER_VERSION
+    } else if let Some(version_label) = version_labels.get_mut(id) {
+        version_label.statuses.insert(status);
+        &id.hash
+    } else if version_labels
+        .keys()
+        .filter(|labelled| labelled.path == id.path)
+        .count()
+        < MAX_VERSION_LABELS_PER_COMPONENT
+    {
+        version_labels.insert(
+            id.clone(),
+            VersionLabel {
+                component_label: component_label.to_string(),
+                statuses: vec![status].into_iter().collect(),
+            },
+        );
+        &id.hash
+    } else {
+        OTHER_VERSION
+    };
+
+    VERSION_REQUESTS
+        .with_label_values(&[component_label, version, &status.to_string()])
+        .inc();
+}
+
+// Drops every series of a version that's gone for good, which also frees its place under the per component limit
+pub fn forget_version(id: &ComponentId) {
+    if let Some(version_label) = VERSION_LABELS.lock().remove(id) {
+        for status in version_label.statuses {
+            let _ = VERSION_REQUESTS.remove_label_values(&[
+                &version_label.component_label,
+                &id.hash,
+                &status.to_string(),
+            ]);
+        }
+    }
+}
+
+// Drops the series that would otherwise keep reporting a removed worker's last value
+pub fn forget_worker(worker_url: &str) {
+    let _ = WORKER_COMPONENTS.remove_label_values(&[worker_url]);
+    let _ = WORKER_HEALTHY.remove_label_values(&[worker_url]);
+}
+
+pub fn encode() -> Result<Vec<u8>, RouterError> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(buffer)
+}
+
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(repo: &str, hash: &str) -> ComponentId {
+        ComponentId {
+            path: ComponentPath {
+                user: "alice".to_string(),
+                repo: repo.to_string(),
+            },
+            hash: hash.to_string(),
+        }
+    }
+
+    fn counted(id: &ComponentId, version_label: &str) -> i64 {
+        let component_label = id.path.to_string();
+        VERSION_REQUESTS
+            .with_label_values(&[&component_label, version_label, "200"])
+            .get()
+    }
+
+    #[test]
+    fn versions_past_the_limit_share_a_label_until_one_is_forgotten() {
+        let versions: Vec<_> = (0..=MAX_VERSION_LABELS_PER_COMPONENT)
+            .map(|n| version("many-versions", &format!("h{}", n)))
+            .collect();
+        let (newest, older) = versions.split_last().unwrap();
+        let component_label = newest.path.to_string();
+
+        for id in older {
+            count_version_request(id, &component_label, 200);
+        }
+        count_version_request(newest, &component_label, 200);
+        assert_eq!(counted(newest, OTHER_VERSION), 1);
+        assert_eq!(counted(newest, &newest.hash), 0);
+
+        forget_version(&older[0]);
+        count_version_request(newest, &component_label, 200);
+        assert_eq!(counted(newest, &newest.hash), 1);
+    }
+}
@@ -100,6 +100,9 @@ pub struct ComponentStats {
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 pub struct ComponentStatus {
     pub id: ComponentId,
+    // Numbered by the deployment manager, higher for every new deployment of the component (older workers leave it out)
+    #[serde(default)]
+    pub generation: Option<u64>,
     #[serde(flatten)]
     pub component_stats: ComponentStats,
 }
@@ -128,3 +131,38 @@ pub struct ComponentResponse {
     pub http_response_code: u32,
     pub error_message: Option<String>,
 }
+
+#[derive(Clone, Copy, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub enum ComponentEventAction {
+    #[serde(rename = "add")]
+    Add,
+    #[serde(rename = "remove")]
+    Remove,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentEvent {
+    pub action: ComponentEventAction,
+    pub component: ComponentId,
+    pub worker: String,
+    // Same as `ComponentStatus::generation`
+    #[serde(default)]
+    pub generation: Option<u64>,
+}
+
+// Pushed to the router by the deployment manager (or a worker), each `source` numbers its own pushes
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentEventsRequest {
+    pub source: String,
+    // Has to go up whenever the source starts numbering from scratch (e.g. its start time), which resets its `seq_num`
+    #[serde(default)]
+    pub epoch: u64,
+    pub seq_num: u64,
+    pub events: Vec<ComponentEvent>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct ComponentEventsResponse {
+    // The component map's own `seq_num`, after the events were applied
+    pub seq_num: u64,
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::rt::{Future, Stream};
+use hyper::{Body, HeaderMap, Method, Request, Response};
+use parking_lot::Mutex;
+
+use crate::body::LimitedBody;
+use crate::error::RouterError;
+use crate::load_balancer::WorkerLoadBalancer;
+use crate::model::{ComponentEventsRequest, ComponentEventsResponse};
+
+pub const COMPONENT_EVENTS_PATH: &str = "/meta/component-events";
+
+// Even a big deployment's worth of events is nowhere near this
+const MAX_PUSH_BODY_BYTES: u64 = 1024 * 1024;
+
+// Takes component changes pushed by the deployment manager (or workers), so they're routed to right away instead of
+// after the next status poll (which carries on as a slower safety net)
+#[derive(Debug)]
+pub struct PushHandler {
+    load_balancer: Arc<WorkerLoadBalancer>,
+    token: String,
+    // The last `(epoch, seq_num)` applied from each source, anything at or below it arrived out of order
+    last_seq_nums: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl PushHandler {
+    pub fn new(load_balancer: Arc<WorkerLoadBalancer>, token: String) -> Self {
+        Self {
+            load_balancer,
+            token,
+            last_seq_nums: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn handle(
+        self: Arc<Self>,
+        req: Request<Body>,
+    ) -> impl Future<Item = Response<Body>, Error = RouterError> + Send {
+        let method = req.method().clone();
+        let headers = req.headers().clone();
+
+        LimitedBody::new(req.into_body(), MAX_PUSH_BODY_BYTES)
+            .concat2()
+            .map_err(RouterError::BodyStream)
+            .and_then(move |body| self.apply(&method, &headers, &body))
+    }
+
+    fn apply(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Response<Body>, RouterError> {
+        if !self.is_authorized(headers) {
+            return Err(RouterError::Unauthorized);
+        }
+        if method != Method::POST {
+            return Err(RouterError::PathNotFound(format!(
+                "{} {}",
+                method, COMPONENT_EVENTS_PATH
+            )));
+        }
+
+        let request: ComponentEventsRequest =
+            serde_json::from_slice(body).map_err(|e| RouterError::InvalidPushEvent(e.to_string()))?;
+
+        // Held while the events are applied, so two pushes from the same source can't overtake each other
+        let mut last_seq_nums = self.last_seq_nums.lock();
+        // Note: A newer epoch (the source restarted) starts its numbering over, so any `seq_num` goes
+        if let Some(&(last_epoch, last_seq_num)) = last_seq_nums.get(&request.source) {
+            if (request.epoch, request.seq_num) <= (last_epoch, last_seq_num) {
+                return Err(RouterError::StalePushEvent(format!(
+                    "{} already pushed seq_num {} in epoch {}, got seq_num {} in epoch {}",
+                    request.source, last_seq_num, last_epoch, request.seq_num, request.epoch
+                )));
+            }
+        }
+
+        let seq_num = self.load_balancer.apply_component_events(&request.events)?;
+        last_seq_nums.insert(request.source, (request.epoch, request.seq_num));
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&ComponentEventsResponse {
+                seq_num,
+            })?))
+            .unwrap())
+    }
+
+    // Expects `Authorization: Bearer <token>`, compared in constant time so the token can't be guessed byte by byte
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let presented = match headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(presented) => presented.as_bytes(),
+            None => return false,
+        };
+        let expected = self.token.as_bytes();
+
+        presented.len() == expected.len()
+            && presented
+                .iter()
+                .zip(expected)
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+}
@@ -0,0 +1,274 @@
+use std::cmp::Reverse;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use hyper::rt::{Future, Stream};
+use tokio::timer::Interval;
+
+use crate::config::ReaperConfig;
+use crate::load_balancer::{VersionWorkers, WorkerLoadBalancer};
+use crate::metrics;
+use crate::model::{ComponentId, ComponentPath, DeactivateRequest, DeactivationStatus};
+use crate::worker::WorkerNode;
+
+// A replica that's been idle for at least `idle_ttl`
+struct IdleReplica {
+    id: ComponentId,
+    worker: Arc<WorkerNode>,
+    idle_time: Duration,
+    // Where the version comes in the component's versions, newest first
+    version_age: usize,
+    // Replicas on workers we aren't sending requests to don't count towards `min_replicas`
+    available: bool,
+}
+
+// Every `check_interval`, deactivates the replicas that have been idle for longer than `idle_ttl`
+// (the router sees every request, so it knows which replicas have gone quiet better than the workers do)
+pub fn idle_reaper(
+    load_balancer: Arc<WorkerLoadBalancer>,
+    config: &ReaperConfig,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let idle_ttl = config.idle_ttl();
+    let min_replicas = config.min_replicas;
+    let check_interval = config.check_interval();
+
+    Interval::new(Instant::now() + check_interval, check_interval)
+        .map_err(|e| error!("Idle reaper timer failed: {}", e))
+        .for_each(move |_| reap_idle_replicas(&load_balancer, idle_ttl, min_replicas))
+}
+
+fn reap_idle_replicas(
+    load_balancer: &Arc<WorkerLoadBalancer>,
+    idle_ttl: Duration,
+    min_replicas: usize,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let mut deactivations = Vec::new();
+
+    for (path, versions) in load_balancer.component_replicas() {
+        for replica in replicas_to_reap(&path, &versions, idle_ttl, min_replicas) {
+            info!(
+                "Deactivating {}@{} on {}, idle for {}s{}",
+                replica.id.path,
+                replica.id.hash,
+                replica.worker.request_url(),
+                replica.idle_time.as_secs(),
+                if replica.available {
+                    ""
+                } else {
+                    " (worker unavailable)"
+                }
+            );
+            deactivations.push(deactivate_replica(&replica.worker, replica.id));
+        }
+    }
+
+    if deactivations.is_empty() {
+        return Either::A(future::ok(()));
+    }
+
+    // Take the deactivated replicas out of routing right away, instead of waiting for the next scheduled refresh
+    let load_balancer = load_balancer.clone();
+    Either::B(future::join_all(deactivations).and_then(move |deactivated| {
+        if deactivated.contains(&true) {
+            Either::A(load_balancer.update_component_map())
+        } else {
+            Either::B(future::ok(()))
+        }
+    }))
+}
+
+// Note: `min_replicas` is per component, so once the component as a whole has enough replicas left, idle replicas of
+// its older versions can all go
+fn replicas_to_reap(
+    path: &ComponentPath,
+    versions: &[VersionWorkers],
+    idle_ttl: Duration,
+    min_replicas: usize,
+) -> Vec<IdleReplica> {
+    let available = versions
+        .iter()
+        .flat_map(|version| &version.workers)
+        .filter(|worker| worker.is_available())
+        .count();
+    let mut removable = available.saturating_sub(min_replicas);
+
+    // Replicas we aren't routing to go first, then the ones that have been idle the longest, then older versions
+    let mut idle_replicas = idle_replicas(path, versions, idle_ttl);
+    idle_replicas.sort_by_key(|replica| {
+        (
+            replica.available,
+            Reverse(replica.idle_time),
+            Reverse(replica.version_age),
+        )
+    });
+
+    idle_replicas
+        .into_iter()
+        .take_while(|replica| {
+            if !replica.available {
+                return true;
+            }
+            if removable == 0 {
+                return false;
+            }
+            removable -= 1;
+            true
+        })
+        .collect()
+}
+
+fn idle_replicas(
+    path: &ComponentPath,
+    versions: &[VersionWorkers],
+    idle_ttl: Duration,
+) -> Vec<IdleReplica> {
+    // Every replica's idle time is measured against the same moment, so equally idle replicas compare equal
+    let now = Instant::now();
+    versions
+        .iter()
+        .enumerate()
+        .flat_map(|(version_age, version)| {
+            let id = ComponentId {
+                path: path.clone(),
+                hash: version.hash.clone(),
+            };
+            version
+                .workers
+                .iter()
+                .map(move |worker| (version_age, id.clone(), worker))
+        })
+        .filter(|(_, id, worker)| worker.version_in_flight_requests(id) == 0)
+        .filter_map(|(version_age, id, worker)| {
+            let idle_time = now.saturating_duration_since(worker.version_last_request(&id)?);
+            Some(IdleReplica {
+                id,
+                worker: worker.clone(),
+                idle_time,
+                version_age,
+                available: worker.is_available(),
+            })
+        })
+        .filter(|replica| replica.idle_time >= idle_ttl)
+        .collect()
+}
+
+// Resolves to whether the replica is gone, failures are only logged (the next check will try again)
+fn deactivate_replica(
+    worker: &Arc<WorkerNode>,
+    id: ComponentId,
+) -> impl Future<Item = bool, Error = ()> + Send {
+    let worker_url = worker.request_url().to_string();
+    let component_label = metrics::component_label(&id.path);
+    let request = DeactivateRequest { id };
+
+    worker.deactivate(&request).then(move |response| {
+        let id = request.id;
+        let (result, deactivated) = match response {
+            Ok(response) => {
+                match response.result {
+                    DeactivationStatus::DeactivationSuccessful => {
+                        info!("Deactivated {}@{} on {}", id.path, id.hash, worker_url);
+                    }
+                    DeactivationStatus::ComponentNotFound => {
+                        info!("{}@{} was already gone from {}", id.path, id.hash, worker_url);
+                    }
+                    ref result => warn!(
+                        "Worker {} answered {:?} deactivating {}@{}: {}",
+                        worker_url, result, id.path, id.hash, response.dbg_message
+                    ),
+                }
+                let deactivated = matches!(
+                    response.result,
+                    DeactivationStatus::DeactivationSuccessful | DeactivationStatus::ComponentNotFound
+                );
+                (deactivation_result_label(&response.result), deactivated)
+            }
+            Err(e) => {
+                warn!(
+                    "Could not deactivate {}@{} on {}: {}",
+                    id.path, id.hash, worker_url, e
+                );
+                ("error", false)
+            }
+        };
+
+        metrics::DEACTIVATIONS
+            .with_label_values(&[&component_label, result])
+            .inc();
+        Ok(deactivated)
+    })
+}
+
+// Matches how the worker API spells each result
+fn deactivation_result_label(result: &DeactivationStatus) -> &'static str {
+    match result {
+        DeactivationStatus::ComponentNotFound => "component-not-found",
+        DeactivationStatus::DeactivationSuccessful => "deactivation-successful",
+        DeactivationStatus::FailedToDeactivate => "failed-to-deactivate",
+        DeactivationStatus::InvalidRequest => "invalid-request",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouterConfig;
+    use reqwest::r#async::Client;
+
+    fn worker() -> Arc<WorkerNode> {
+        let config = RouterConfig::default();
+        Arc::new(WorkerNode::new(
+            "http://v9_w1.example.com".to_string(),
+            Client::new(),
+            config.timeouts.worker_status(),
+            config.health,
+            config.circuit_breaker,
+        ))
+    }
+
+    fn version(hash: &str) -> ComponentId {
+        ComponentId {
+            path: ComponentPath {
+                user: "alice".to_string(),
+                repo: "app".to_string(),
+            },
+            hash: hash.to_string(),
+        }
+    }
+
+    // Both versions on the same worker, newest first (the order `component_replicas` gives them in)
+    fn versions(worker: &Arc<WorkerNode>) -> Vec<VersionWorkers> {
+        ["new", "old"]
+            .iter()
+            .map(|hash| VersionWorkers {
+                hash: (*hash).to_string(),
+                workers: vec![worker.clone()],
+            })
+            .collect()
+    }
+
+    fn reaped_hashes(worker: &Arc<WorkerNode>) -> Vec<String> {
+        replicas_to_reap(&version("new").path, &versions(worker), Duration::from_secs(0), 1)
+            .into_iter()
+            .map(|replica| replica.id.hash)
+            .collect()
+    }
+
+    #[test]
+    fn traffic_to_the_new_version_leaves_the_old_one_idle() {
+        let worker = worker();
+        worker.track_running_components(&[version("old"), version("new")]);
+        drop(worker.start_request(&version("new")));
+
+        assert_eq!(reaped_hashes(&worker), vec!["old"]);
+    }
+
+    #[test]
+    fn older_versions_go_first_when_equally_idle() {
+        let worker = worker();
+        worker.track_running_components(&[version("new"), version("old")]);
+
+        assert_eq!(reaped_hashes(&worker), vec!["old"]);
+    }
+}
@@ -1,115 +1,527 @@
-use std::env;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use hyper::{Body, Method, Response, StatusCode};
+use futures::future::{self, Either, Loop};
+use hyper::rt::{Future, Stream};
+use hyper::{Body, Chunk, HeaderMap, Method, Response, StatusCode};
+use reqwest::r#async::Client;
+use tokio::timer::Delay;
+use tokio::util::FutureExt;
 
+use crate::activation::Activator;
+use crate::balancing::Affinity;
+use crate::body::{InFlightBody, LimitedBody, RequestBody};
+use crate::config::{RetryOn, RouterConfig, StickyConfig};
 use crate::error::RouterError;
-use crate::load_balancer::WorkerLoadBalancer;
-use crate::model::ComponentPath;
-use crate::worker::WorkerNode;
+use crate::headers;
+use crate::load_balancer::{SelectedWorker, WorkerLoadBalancer};
+use crate::metrics;
+use crate::model::{ComponentId, ComponentPath};
+use crate::retry::{RetryPolicies, RetryPolicy};
+use crate::worker::{FailureScope, WorkerNode};
 
-#[derive(Clone)]
 pub struct ComponentRequest {
     http_verb: Method,
     query: String,
-    body: String,
+    headers: HeaderMap,
+    body: RequestBody,
     user: String,
     repo: String,
     method: String,
+    // The version (`ComponentId::hash`) the client pinned, if any
+    version: Option<String>,
+    client_addr: IpAddr,
 }
 
 impl ComponentRequest {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         http_verb: Method,
         query: String,
-        body: String,
+        headers: HeaderMap,
+        body: RequestBody,
         user: String,
         repo: String,
         method: String,
+        version: Option<String>,
+        client_addr: IpAddr,
     ) -> Self {
         Self {
             http_verb,
             query,
+            headers,
             body,
             user,
             repo,
             method,
+            version,
+            client_addr,
+        }
+    }
+
+    // Requests for sticky components stick by whichever configured key they have, everything else by client address
+    fn affinity(&self, sticky: Option<&StickyConfig>) -> Affinity {
+        let sticky_key = sticky.and_then(|sticky| {
+            let cookie = || {
+                let name = sticky.cookie.as_ref()?;
+                headers::cookie_value(&self.headers, name)
+            };
+            let header = || {
+                let name = sticky.header.as_ref()?;
+                let value = self.headers.get(name.as_str())?.to_str().ok()?;
+                Some(value.to_string())
+            };
+            let query = || {
+                let name = sticky.query.as_ref()?;
+                self.query
+                    .split('&')
+                    .filter_map(|pair| {
+                        let mut parts = pair.splitn(2, '=');
+                        Some((parts.next()?, parts.next()?))
+                    })
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string())
+            };
+
+            cookie().or_else(header).or_else(query)
+        });
+
+        match sticky_key {
+            Some(key) => Affinity::Sticky(key),
+            None => Affinity::Client(self.client_addr.to_string()),
         }
     }
 }
 
+struct WorkerResponse {
+    response: Response<Body>,
+    // The worker didn't actually have the component, so our component map was out of date
+    found_stale_data: bool,
+}
+
 #[derive(Debug)]
 pub struct RequestForwarder {
+    // This is shared by every request (and every worker's status polls), so connections are pooled
+    client: Client,
     load_balancer: Arc<WorkerLoadBalancer>,
+    // Only there if scale from zero is turned on
+    activator: Option<Arc<Activator>>,
+    retry_policies: RetryPolicies,
+    max_response_body_bytes: u64,
 }
 
 impl RequestForwarder {
-    pub fn new() -> Self {
-        // If loading from the environment variable fails, there was a user error and we should bail
-        // TODO: Get this from dependency injection
-        let worker_string = match env::var("V9_WORKERS") {
-            Ok(value) => value,
-            Err(e) => panic!("No V9_WORKERS env variable set: {:?}", e),
-        };
+    pub fn new(config: &RouterConfig) -> Result<Self, RouterError> {
+        // Automatic gzip decoding is off, since we pass Accept-Encoding / Content-Encoding through untouched
+        let client = Client::builder()
+            .timeout(config.timeouts.forward())
+            .gzip(false)
+            .build()
+            .unwrap();
 
-        let workers = worker_string
-            .split(';')
-            .map(|worker_url| WorkerNode::new(worker_url.to_string()))
-            .collect();
+        let load_balancer = WorkerLoadBalancer::new(client.clone(), config);
+        let activator = Activator::new(load_balancer.clone(), &config.activation)?.map(Arc::new);
 
-        Self {
-            load_balancer: WorkerLoadBalancer::new(workers),
-        }
+        Ok(Self {
+            load_balancer,
+            activator,
+            client,
+            retry_policies: RetryPolicies::new(&config.retry, &config.timeouts),
+            max_response_body_bytes: config.limits.max_response_body_bytes,
+        })
+    }
+
+    pub fn background_tasks(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.load_balancer.clone().background_updater()
+    }
+
+    pub fn load_balancer(&self) -> Arc<WorkerLoadBalancer> {
+        self.load_balancer.clone()
+    }
+
+    pub fn activator(&self) -> Option<Arc<Activator>> {
+        self.activator.clone()
     }
 
     fn send_request_to_worker(
-        request: ComponentRequest,
-        worker_url: &str,
-    ) -> Result<(StatusCode, String), RouterError> {
+        client: &Client,
+        request: &mut ComponentRequest,
+        selected: &SelectedWorker,
+        per_try_timeout: Duration,
+        max_response_body_bytes: u64,
+    ) -> impl Future<Item = WorkerResponse, Error = RouterError> {
+        let worker = &selected.worker;
         let mut url = format!(
             "{}/sl/{}/{}/{}",
-            worker_url, request.user, request.repo, request.method
+            worker.request_url(),
+            request.user,
+            request.repo,
+            request.method
         );
 
         if !request.query.is_empty() {
             url = format!("{}?{}", url, request.query);
         }
 
-        // TODO: This blocks the executor, so we probably should do something smarter than just blocking
-        let client = reqwest::Client::new();
-        let mut worker_resp = client
-            .request(request.http_verb, &url)
-            .body(request.body)
-            .send()?;
+        let body_limit_exceeded = request.body.limit_exceeded_flag();
+        let body = request
+            .body
+            .take_for_worker()
+            .expect("A streamed request body can only be sent to one worker");
+        let path = ComponentPath {
+            user: request.user.clone(),
+            repo: request.repo.clone(),
+        };
+        let version = ComponentId {
+            path: path.clone(),
+            hash: selected.version.clone(),
+        };
+        let in_flight = worker.start_request(&version);
+        let component_label = metrics::component_label(&path);
+        let worker_url = worker.request_url().to_string();
+        let failed_worker = worker.clone();
+        let failed_path = path.clone();
+        let responding_worker = worker.clone();
+        let send_started = Instant::now();
+
+        client
+            .request(request.http_verb.clone(), &url)
+            .headers(request.headers.clone())
+            .body(body)
+            .send()
+            .map_err(RouterError::from)
+            .timeout(per_try_timeout)
+            .map_err(move |e| {
+                // If we cut the client's body off for being too big, that's the real reason this failed
+                if body_limit_exceeded.is_some_and(|flag| flag.load(Ordering::SeqCst)) {
+                    RouterError::RequestBodyTooLarge
+                } else {
+                    // Otherwise it counts against the worker or the component, depending on what went wrong
+                    let e = RouterError::from(e);
+                    failed_worker.record_request_failure(&failed_path, &e, failure_scope(&e));
+                    e
+                }
+            })
+            .and_then(move |worker_resp| {
+                let latency = send_started.elapsed();
+                metrics::WORKER_REQUEST_DURATION
+                    .with_label_values(&[&worker_url])
+                    .observe(latency.as_secs_f64());
+                responding_worker.record_latency(&path, latency);
+
+                let status = worker_resp.status();
+                if status.is_server_error() {
+                    let reason = format!("responded with {}", status);
+                    responding_worker.record_request_failure(&path, &reason, FailureScope::Component);
+                } else {
+                    responding_worker.record_request_success(&path);
+                }
+
+                let mut headers = worker_resp.headers().clone();
+                headers::prepare_response_headers(&mut headers);
+
+                if worker_resp
+                    .content_length()
+                    .is_some_and(|content_length| content_length > max_response_body_bytes)
+                {
+                    return Either::A(future::err(RouterError::ResponseBodyTooLarge));
+                }
+
+                let body = LimitedBody::new(worker_resp.into_body(), max_response_body_bytes);
+
+                if status == StatusCode::NOT_FOUND {
+                    // We need to look inside 404s to detect stale data (luckily they are tiny, so we can buffer them)
+                    let limit_exceeded = body.limit_exceeded();
+                    Either::B(Either::A(body.concat2().then(
+                        move |body_result| match body_result {
+                            Ok(body) => Ok(WorkerResponse {
+                                found_stale_data: body.starts_with(b"v9: worker 404"),
+                                response: build_response(status, headers, Body::from(Chunk::from(body))),
+                            }),
+                            Err(_) if limit_exceeded.load(Ordering::SeqCst) => {
+                                Err(RouterError::ResponseBodyTooLarge)
+                            }
+                            Err(e) => Err(RouterError::BodyStream(e)),
+                        },
+                    )))
+                } else {
+                    // Everything else is streamed back to the client as it arrives
+                    // (the worker counts this as in flight until the body stream is done with)
+                    let body = InFlightBody::new(body, in_flight);
+                    Either::B(Either::B(future::ok(WorkerResponse {
+                        response: build_response(status, headers, Body::wrap_stream(body)),
+                        found_stale_data: false,
+                    })))
+                }
+            })
+            .then(move |result| {
+                let status = match &result {
+                    Ok(worker_response) => worker_response.response.status().as_u16(),
+                    Err(e) => e.status_code(),
+                };
+                metrics::count_version_request(&version, &component_label, status);
 
-        Ok((worker_resp.status(), worker_resp.text()?))
+                result
+            })
     }
 
-    #[allow(clippy::single_match_else)]
-    pub fn forward_request(&self, request: ComponentRequest) -> Result<Response<Body>, RouterError> {
+    pub fn forward_request(
+        &self,
+        request: ComponentRequest,
+    ) -> impl Future<Item = Response<Body>, Error = RouterError> + Send {
         let path = ComponentPath {
             user: request.user.clone(),
             repo: request.repo.clone(),
         };
 
-        let worker = match self.load_balancer.get_worker(&path) {
-            Some(worker) => worker,
-            None => return Err(RouterError::PathNotFound(format!("no such component: {}", path))),
+        let affinity = request.affinity(self.load_balancer.sticky_config(&path));
+        let version = request.version.clone();
+
+        let selected = match self
+            .load_balancer
+            .get_worker(&path, version.as_deref(), &affinity)
+        {
+            Ok(selected) => Either::A(future::ok(selected)),
+            // Nobody is running the component, but we know how to start it, so hold the request until it's up
+            Err(RouterError::PathNotFound(_)) if self.can_activate(&path, version.as_deref()) => {
+                let activation = self.activator.as_ref().unwrap().activate(&path);
+                Either::B(Either::A(self.get_worker_after(
+                    activation,
+                    &path,
+                    version.as_deref(),
+                    &affinity,
+                )))
+            }
+            // Or it may just be too new for the component map, so hold the request until a refresh brings it in
+            Err(RouterError::PathNotFound(_)) if self.can_queue(&path) => {
+                let load_balancer = &self.load_balancer;
+                let waiting = load_balancer
+                    .cold_start_queue()
+                    .unwrap()
+                    .wait_for(&path, &|path| load_balancer.has_component(path));
+                Either::B(Either::B(self.get_worker_after(
+                    waiting,
+                    &path,
+                    version.as_deref(),
+                    &affinity,
+                )))
+            }
+            Err(e) => return Either::A(future::err(e)),
         };
 
-        // First attempt naively
-        let (mut code, mut text) = Self::send_request_to_worker(request.clone(), worker.request_url())?;
+        let retries = Retries {
+            client: self.client.clone(),
+            load_balancer: self.load_balancer.clone(),
+            policy: self.retry_policies.policy_for(&path),
+            path,
+            version,
+            affinity,
+            max_response_body_bytes: self.max_response_body_bytes,
+        };
 
-        // If we detect stale data
-        if code == StatusCode::from_u16(404).unwrap() && text.starts_with("v9: worker 404") {
-            // Then retry if we can find a new worker
-            if let Ok(Some(worker)) = self.load_balancer.get_worker_found_stale_data(&path) {
-                let second_req = Self::send_request_to_worker(request, worker.request_url())?;
-                code = second_req.0;
-                text = second_req.1;
-            }
+        Either::B(selected.and_then(move |selected| retries.send(request, selected)))
+    }
+
+    fn get_worker_after<F>(
+        &self,
+        wait: F,
+        path: &ComponentPath,
+        version: Option<&str>,
+        affinity: &Affinity,
+    ) -> impl Future<Item = SelectedWorker, Error = RouterError> + Send
+    where
+        F: Future<Item = (), Error = RouterError> + Send,
+    {
+        let load_balancer = self.load_balancer.clone();
+        let path = path.clone();
+        let version = version.map(str::to_string);
+        let affinity = affinity.clone();
+
+        wait.and_then(move |()| load_balancer.get_worker(&path, version.as_deref(), &affinity))
+    }
+
+    // Only for components that aren't in the map but plausibly exist, a pinned version we don't know about is still a
+    // 404 (and so are made up components, so they can't fill the queue up for everyone else)
+    fn can_queue(&self, path: &ComponentPath) -> bool {
+        self.load_balancer.cold_start_queue().is_some()
+            && !self.load_balancer.has_component(path)
+            && (self.can_activate(path, None) || self.load_balancer.recently_seen(path))
+    }
+
+    fn can_activate(&self, path: &ComponentPath, version: Option<&str>) -> bool {
+        self.activator
+            .as_ref()
+            .is_some_and(|activator| activator.can_activate(path, version))
+    }
+}
+
+// Everything the tries of one request have in common
+#[derive(Clone, Debug)]
+struct Retries {
+    client: Client,
+    load_balancer: Arc<WorkerLoadBalancer>,
+    policy: Arc<RetryPolicy>,
+    path: ComponentPath,
+    version: Option<String>,
+    affinity: Affinity,
+    max_response_body_bytes: u64,
+}
+
+struct Attempt {
+    request: ComponentRequest,
+    selected: SelectedWorker,
+    // Counting from 1
+    number: u32,
+    // Every worker an earlier attempt went to
+    tried: Vec<Arc<WorkerNode>>,
+}
+
+impl Retries {
+    fn send(
+        self,
+        request: ComponentRequest,
+        selected: SelectedWorker,
+    ) -> impl Future<Item = Response<Body>, Error = RouterError> + Send {
+        let first_attempt = Attempt {
+            request,
+            selected,
+            number: 1,
+            tried: Vec::new(),
+        };
+
+        future::loop_fn(first_attempt, move |attempt| self.clone().try_once(attempt))
+            .and_then(|result| result.map(|worker_response| worker_response.response))
+    }
+
+    // Breaks with what the client should get, or continues with the next attempt
+    fn try_once(
+        self,
+        mut attempt: Attempt,
+    ) -> impl Future<Item = Loop<Result<WorkerResponse, RouterError>, Attempt>, Error = RouterError> + Send
+    {
+        RequestForwarder::send_request_to_worker(
+            &self.client,
+            &mut attempt.request,
+            &attempt.selected,
+            self.policy.per_try_timeout(),
+            self.max_response_body_bytes,
+        )
+        .then(move |result| {
+            // Whatever happened, this try is done with its breaker trials (any it didn't record a result for go back)
+            attempt.selected.circuit_permit = None;
+
+            // Only buffered bodies can be sent again, a streamed one is gone once the first try has it
+            let reason = match retry_reason(&result) {
+                Some(reason)
+                    if attempt.request.body.is_replayable()
+                        && self.policy.should_retry(
+                            reason,
+                            &attempt.request.http_verb,
+                            attempt.number,
+                        ) =>
+                {
+                    reason
+                }
+                _ => return Either::A(future::ok(Loop::Break(result))),
+            };
+            let component_label = metrics::component_label(&self.path);
+            metrics::RETRIES
+                .with_label_values(&[&component_label, retry_reason_label(reason)])
+                .inc();
+
+            // Stale data is fixed up before we pick again, anything else just backs off for a bit
+            let wait = if reason == RetryOn::StaleData {
+                metrics::STALE_DATA_RETRIES
+                    .with_label_values(&[&component_label])
+                    .inc();
+                Either::A(
+                    self.load_balancer
+                        .clone()
+                        .found_stale_data(&self.path, &attempt.selected),
+                )
+            } else {
+                let backoff = self.policy.backoff(attempt.number);
+                Either::B(Delay::new(Instant::now() + backoff).then(|_| Ok(())))
+            };
+
+            // If there's nobody left to try, the client gets whatever the last try got
+            Either::B(wait.then(move |_| {
+                attempt.tried.push(attempt.selected.worker);
+                match self.load_balancer.get_worker_excluding(
+                    &self.path,
+                    self.version.as_deref(),
+                    &self.affinity,
+                    &attempt.tried,
+                ) {
+                    Ok(selected) => Ok(Loop::Continue(Attempt {
+                        request: attempt.request,
+                        selected,
+                        number: attempt.number + 1,
+                        tried: attempt.tried,
+                    })),
+                    Err(_) => Ok(Loop::Break(result)),
+                }
+            }))
+        })
+    }
+}
+
+fn build_response(status: StatusCode, headers: HeaderMap, body: Body) -> Response<Body> {
+    let mut response = Response::builder().status(status).body(body).unwrap();
+    *response.headers_mut() = headers;
+    response
+}
+
+// Why a try is worth repeating on another worker, if it is
+fn retry_reason(result: &Result<WorkerResponse, RouterError>) -> Option<RetryOn> {
+    match result {
+        Ok(worker_response) if worker_response.found_stale_data => Some(RetryOn::StaleData),
+        Ok(worker_response) if worker_response.response.status() == StatusCode::SERVICE_UNAVAILABLE => {
+            Some(RetryOn::Unavailable)
         }
+        Err(e) if is_timeout(e) => Some(RetryOn::Timeout),
+        Err(e) if is_unreachable(e) => Some(RetryOn::ConnectFailure),
+        Ok(_) | Err(_) => None,
+    }
+}
+
+fn failure_scope(e: &RouterError) -> FailureScope {
+    if is_unreachable(e) {
+        FailureScope::Worker
+    } else if is_timeout(e) {
+        FailureScope::WorkerAndComponent
+    } else {
+        FailureScope::Component
+    }
+}
+
+fn is_timeout(e: &RouterError) -> bool {
+    match e {
+        RouterError::WorkerTimeout => true,
+        RouterError::InvalidRequest(e) => e.is_timeout(),
+        _ => false,
+    }
+}
+
+fn is_unreachable(e: &RouterError) -> bool {
+    matches!(e, RouterError::InvalidRequest(e) if is_connect_failure(e))
+}
+
+// Hyper only calls it a connect error if the connection was never made, so nothing was sent
+fn is_connect_failure(e: &reqwest::Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<hyper::Error>())
+        .is_some_and(hyper::Error::is_connect)
+}
 
-        Ok(Response::builder().status(code).body(Body::from(text)).unwrap())
+// Matches how the retry config spells each reason
+fn retry_reason_label(reason: RetryOn) -> &'static str {
+    match reason {
+        RetryOn::ConnectFailure => "connect_failure",
+        RetryOn::Timeout => "timeout",
+        RetryOn::Unavailable => "unavailable",
+        RetryOn::StaleData => "stale_data",
     }
 }
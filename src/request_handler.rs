@@ -1,10 +1,21 @@
-use std::str;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
-use hyper::rt::{Future, Stream};
-use hyper::{Body, Method, Request, Response, Uri};
+use futures::future::{self, Either};
+use hyper::body::Payload;
+use hyper::rt::Future;
+use hyper::{Body, HeaderMap, Method, Request, Response, Uri};
 
+use crate::activation::Activator;
+use crate::body::RequestBody;
+use crate::config::RouterConfig;
 use crate::error::RouterError;
+use crate::headers;
+use crate::load_balancer::WorkerLoadBalancer;
+use crate::metrics;
+use crate::model::ComponentPath;
+use crate::push::{PushHandler, COMPONENT_EVENTS_PATH};
 use crate::request_forwarder::{ComponentRequest, RequestForwarder};
 
 // Warning: This method is somewhat complicated, since it needs to deal with async stuff
@@ -12,57 +23,126 @@ use crate::request_forwarder::{ComponentRequest, RequestForwarder};
 // TODO: Deal with panics bubbling up to this level
 pub fn global_request_entrypoint(
     handler: Arc<HttpRequestHandler>,
+    remote_addr: SocketAddr,
     req: Request<Body>,
 ) -> impl Future<Item = Response<Body>, Error = hyper::error::Error> + Send {
     debug!("{:?}", req);
+    let request_started = Instant::now();
+
+    // Pushed component events are the router's own business, so they skip the per-component request metrics
+    if req.uri().path() == COMPONENT_EVENTS_PATH {
+        if let Some(push_handler) = handler.push_handler.clone() {
+            return Either::A(push_handler.handle(req).then(|resp_result| {
+                Ok(resp_result.unwrap_or_else(|e| {
+                    warn!("Rejected pushed component events: {}", e);
+                    e.into()
+                }))
+            }));
+        }
+    }
 
     // Pull the verb, uri, and query stuff out of the request
     // (It's okay to do this, since it's all quite quick to execute)
     let http_verb = req.method().clone();
     let uri = req.uri().clone();
     let query = uri.query().unwrap_or("").to_string();
+    let component_label = handler.component_label(&uri);
+    let content_length = req.body().content_length();
+    let mut headers = req.headers().clone();
+    headers::prepare_request_headers(&mut headers, remote_addr);
 
     // Then get a future representing the body (this is a future, since hyper may not of received the whole body yet)
-    let body_future = req.into_body().concat2().map(|c| {
-        // Convert the Chunk into a rust "String", wrapping any error in our error type
-        str::from_utf8(&c).map(str::to_owned).map_err(RouterError::from)
-    });
+    // Note: Small bodies are buffered as raw bytes, anything bigger gets streamed through to the worker
+    let body_future =
+        RequestBody::from_client(req.into_body(), content_length, handler.max_request_body_bytes);
 
     // Next we want to an operation on the body. This needs to happen in a future for two reasons
     // 1) We want to handle many requests at once, so we don't want to block a thread
     // 2) Hyper literally doesn't let you deal with the body unless you're inside a future context (there is no API to escape this)
-    // Note: We already have a result (body_result) here, since we might get an Utf8 decode error above
-    body_future.map(move |body_result| {
-        debug!("body = {:?}", body_result);
-
-        let resp: Response<Body> = body_result
-            // Delegate to the handler to actually deal with this request
-            .and_then(|body| handler.handle(http_verb, &uri, query, body))
-            .unwrap_or_else(|e| {
-                warn!("Forced to convert error {:?} into a http response", e);
-                e.into()
-            });
-
-        if resp.status() == 532 {
-            error!("INTERNAL ROUTER ERROR -- {:?}", resp);
-        } else {
-            debug!("{:?}", resp);
-        }
+    // Note: Forwarding to the worker is itself a future, so nothing here ever blocks the executor
+    Either::B(
+        body_future
+            .and_then(move |body| {
+                // Delegate to the handler to actually deal with this request
+                handler.handle(http_verb, &uri, query, headers, body, remote_addr)
+            })
+            .then(move |resp_result| {
+                let resp: Response<Body> = resp_result.unwrap_or_else(|e| {
+                    warn!("Forced to convert error {:?} into a http response", e);
+                    e.into()
+                });
+
+                metrics::REQUESTS
+                    .with_label_values(&[&component_label, resp.status().as_str()])
+                    .inc();
+                metrics::REQUEST_DURATION
+                    .with_label_values(&[&component_label])
+                    .observe(request_started.elapsed().as_secs_f64());
 
-        resp
-    })
+                if resp.status() == 532 {
+                    error!("INTERNAL ROUTER ERROR -- {:?}", resp);
+                } else {
+                    debug!("{:?}", resp);
+                }
+
+                Ok(resp)
+            }),
+    )
 }
 
 #[derive(Debug)]
 pub struct HttpRequestHandler {
     // Contents of this handler need to be thread-safe
     request_forwarder: RequestForwarder,
+    max_request_body_bytes: u64,
+    // Only there when a push token is configured
+    push_handler: Option<Arc<PushHandler>>,
 }
 
 impl HttpRequestHandler {
-    pub fn new() -> Self {
-        Self {
-            request_forwarder: RequestForwarder::new(),
+    pub fn new(config: &RouterConfig) -> Result<Self, RouterError> {
+        let request_forwarder = RequestForwarder::new(config)?;
+        let push_handler = config
+            .push
+            .token
+            .clone()
+            .map(|token| Arc::new(PushHandler::new(request_forwarder.load_balancer(), token)));
+
+        Ok(Self {
+            request_forwarder,
+            max_request_body_bytes: config.limits.max_request_body_bytes,
+            push_handler,
+        })
+    }
+
+    pub fn background_tasks(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.request_forwarder.background_tasks()
+    }
+
+    pub fn load_balancer(&self) -> Arc<WorkerLoadBalancer> {
+        self.request_forwarder.load_balancer()
+    }
+
+    pub fn activator(&self) -> Option<Arc<Activator>> {
+        self.request_forwarder.activator()
+    }
+
+    // Metrics are only labelled with components we're actually routing to, so junk paths can't make up new labels
+    fn component_label(&self, uri: &Uri) -> String {
+        let path_components: Vec<&str> = uri.path().split('/').skip(1).collect();
+        if path_components.len() < 4 {
+            return metrics::UNKNOWN_COMPONENT.to_string();
+        }
+
+        let (repo, _) = split_version(path_components[2]);
+        let path = ComponentPath {
+            user: path_components[1].to_string(),
+            repo,
+        };
+        if self.request_forwarder.load_balancer().has_component(&path) {
+            metrics::component_label(&path)
+        } else {
+            metrics::UNKNOWN_COMPONENT.to_string()
         }
     }
 
@@ -71,20 +151,50 @@ impl HttpRequestHandler {
         http_verb: Method,
         uri: &Uri,
         query: String,
-        body: String,
-    ) -> Result<Response<Body>, RouterError> {
+        headers: HeaderMap,
+        body: RequestBody,
+        remote_addr: SocketAddr,
+    ) -> impl Future<Item = Response<Body>, Error = RouterError> + Send {
         // Get the uri path, and then split it around slashes into components
         // Note: All URIs start with a slash, so we skip the first entry in the split (which is always just "")
         let path_components: Vec<&str> = uri.path().split('/').skip(1).collect();
         if path_components.len() < 4 {
-            return Err(RouterError::PathNotFound(path_components.join("/")));
+            return Either::A(future::err(RouterError::PathNotFound(path_components.join("/"))));
         }
 
         let user = path_components[1].to_string();
-        let repo = path_components[2].to_string();
+        let (repo, path_version) = split_version(path_components[2]);
         let method = path_components[3].to_string();
 
-        let request = ComponentRequest::new(http_verb, query, body, user, repo, method);
-        Ok(self.request_forwarder.forward_request(request)?)
+        // A version pinned in the path wins over one pinned by header, which wins over one pinned by cookie
+        let version = path_version
+            .or_else(|| {
+                headers
+                    .get(headers::X_V9_COMPONENT_HASH)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            })
+            .or_else(|| headers::cookie_value(&headers, headers::V9_COMPONENT_HASH_COOKIE));
+
+        let request = ComponentRequest::new(
+            http_verb,
+            query,
+            headers,
+            body,
+            user,
+            repo,
+            method,
+            version,
+            remote_addr.ip(),
+        );
+        Either::B(self.request_forwarder.forward_request(request))
     }
 }
+
+// Splits a "repo@hash" path segment into the repo and the version (hash) it pins, if any
+fn split_version(repo_segment: &str) -> (String, Option<String>) {
+    let mut parts = repo_segment.splitn(2, '@');
+    let repo = parts.next().unwrap_or("").to_string();
+    let version = parts.next().filter(|hash| !hash.is_empty()).map(str::to_string);
+    (repo, version)
+}
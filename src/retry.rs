@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Method;
+
+use crate::config::{RetryConfig, RetryOn, RetryOverride, TimeoutConfig};
+use crate::model::ComponentPath;
+
+// Past this many doublings the backoff is at `max_backoff` anyway
+const MAX_BACKOFF_DOUBLINGS: u32 = 16;
+
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    per_try_timeout: Duration,
+    backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<RetryOn>,
+}
+
+impl RetryPolicy {
+    fn new(
+        config: &RetryConfig,
+        timeouts: &TimeoutConfig,
+        retry_override: Option<&RetryOverride>,
+    ) -> Self {
+        let retry_override = retry_override.cloned().unwrap_or_default();
+
+        Self {
+            max_attempts: retry_override.max_attempts.unwrap_or(config.max_attempts),
+            per_try_timeout: retry_override
+                .per_try_timeout_ms
+                .or(config.per_try_timeout_ms)
+                .map_or_else(|| timeouts.forward(), Duration::from_millis),
+            backoff: Duration::from_millis(retry_override.backoff_ms.unwrap_or(config.backoff_ms)),
+            max_backoff: Duration::from_millis(
+                retry_override.max_backoff_ms.unwrap_or(config.max_backoff_ms),
+            ),
+            retry_on: retry_override.retry_on.unwrap_or_else(|| config.retry_on.clone()),
+        }
+    }
+
+    pub fn per_try_timeout(&self) -> Duration {
+        self.per_try_timeout
+    }
+
+    // Whether a request that has been tried `attempts` times, the last one failing because of `reason`, gets another go
+    pub fn should_retry(&self, reason: RetryOn, method: &Method, attempts: u32) -> bool {
+        attempts < self.max_attempts
+            && self.retry_on.contains(&reason)
+            && (never_reached_component(reason) || is_idempotent(method))
+    }
+
+    // How long to wait before retry number `retry` (the first retry is 1)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let doublings = retry.saturating_sub(1).min(MAX_BACKOFF_DOUBLINGS);
+        self.backoff
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+pub struct RetryPolicies {
+    default_policy: Arc<RetryPolicy>,
+    overrides: HashMap<ComponentPath, Arc<RetryPolicy>>,
+}
+
+impl RetryPolicies {
+    pub fn new(config: &RetryConfig, timeouts: &TimeoutConfig) -> Self {
+        Self {
+            default_policy: Arc::new(RetryPolicy::new(config, timeouts, None)),
+            overrides: config
+                .component_overrides()
+                .into_iter()
+                .map(|(path, retry_override)| {
+                    let policy = RetryPolicy::new(config, timeouts, Some(&retry_override));
+                    (path, Arc::new(policy))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn policy_for(&self, path: &ComponentPath) -> Arc<RetryPolicy> {
+        self.overrides.get(path).unwrap_or(&self.default_policy).clone()
+    }
+}
+
+// These failures mean the component provably never saw the request, so even a POST can safely be sent again
+fn never_reached_component(reason: RetryOn) -> bool {
+    match reason {
+        RetryOn::ConnectFailure | RetryOn::StaleData => true,
+        RetryOn::Timeout | RetryOn::Unavailable => false,
+    }
+}
+
+// As RFC 7231 defines it: sending one of these twice has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
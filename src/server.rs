@@ -1,34 +1,50 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use hyper::rt::{self, Future};
-use hyper::service::service_fn;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 
-const PRODUCTION_PORT: u16 = 80;
-const DEVELOPMENT_PORT: u16 = 8080;
+pub type BackgroundTask = Box<dyn Future<Item = (), Error = ()> + Send>;
 
-pub fn start_server<S, F>(development_mode: bool, state: Arc<S>, handler: fn(Arc<S>, Request<Body>) -> F)
-where
+pub fn start_server<S, F>(
+    addr: SocketAddr,
+    state: Arc<S>,
+    handler: fn(Arc<S>, SocketAddr, Request<Body>) -> F,
+    background_tasks: Vec<BackgroundTask>,
+) where
     S: Send + Sync + 'static,
     F: Future<Item = Response<Body>, Error = hyper::error::Error> + Send + 'static,
 {
-    let port = if development_mode {
-        DEVELOPMENT_PORT
-    } else {
-        PRODUCTION_PORT
-    };
-
-    let addr = ([0, 0, 0, 0], port).into();
+    info!("Starting Server on {}...", addr);
+    rt::run(rt::lazy(move || {
+        // Background tasks can only be spawned once we're inside the runtime
+        // (each one is spawned separately, so one of them stopping doesn't take the others down)
+        for background_task in background_tasks {
+            rt::spawn(background_task);
+        }
+        serve(addr, state, handler)
+    }));
+}
 
-    let new_service = move || {
+// A future that serves `handler` on `addr` until it hits an error (the admin listener runs as one of these too)
+pub fn serve<S, F>(
+    addr: SocketAddr,
+    state: Arc<S>,
+    handler: fn(Arc<S>, SocketAddr, Request<Body>) -> F,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: Send + Sync + 'static,
+    F: Future<Item = Response<Body>, Error = hyper::error::Error> + Send + 'static,
+{
+    let new_service = make_service_fn(move |conn: &AddrStream| {
         let copied_state = state.clone();
-        service_fn(move |req| handler(copied_state.clone(), req))
-    };
+        let remote_addr = conn.remote_addr();
+        service_fn(move |req| handler(copied_state.clone(), remote_addr, req))
+    });
 
-    let server = Server::bind(&addr)
+    Server::bind(&addr)
         .serve(new_service)
-        .map_err(|e| error!("server error: {}", e));
-
-    info!("Starting Server...");
-    rt::run(server);
+        .map_err(move |e| error!("server error on {}: {}", addr, e))
 }
@@ -1,54 +1,768 @@
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
+use hyper::rt::Future;
+use parking_lot::Mutex;
+use reqwest::r#async::Client;
+use tokio::util::FutureExt;
 
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerStatus, CircuitState};
+use crate::config::{CircuitBreakerConfig, HealthConfig};
 use crate::error::RouterError;
-use crate::model::{ComponentPath, StatusResponse};
-
-const WORKER_TIMEOUT: Duration = Duration::from_secs(3);
+use crate::metrics;
+use crate::model::{
+    ActivateRequest, ActivateResponse, ComponentId, ComponentPath, ComponentStats, DeactivateRequest,
+    DeactivateResponse, StatusResponse,
+};
 
 #[derive(Debug)]
 pub struct WorkerNode {
     client: Client,
     url: String,
+    status_timeout: Duration,
+    refresh_state: Mutex<WorkerRefreshState>,
+    health_config: HealthConfig,
+    health: Mutex<HealthState>,
+    circuit_breaker_config: CircuitBreakerConfig,
+    // Trips when the worker can't be reached at all
+    circuit_breaker: CircuitBreaker,
+    // Trip when one component on the worker keeps failing, only created once it has failed
+    component_breakers: Mutex<HashMap<ComponentPath, CircuitBreaker>>,
+    scheduling: Mutex<Scheduling>,
+    in_flight: AtomicUsize,
+    // The same requests as `in_flight`, split up by component (entries are shared with the guards counting them)
+    component_in_flight: Mutex<HashMap<ComponentPath, Arc<AtomicUsize>>>,
+    // The same requests again, split up by the version they were sent to (so the reaper can tell versions apart)
+    version_in_flight: Mutex<HashMap<ComponentId, Arc<AtomicUsize>>>,
+    component_latencies: Mutex<HashMap<ComponentPath, ComponentLatency>>,
+    // When we last sent each version running here a request (or first saw it running, if we never have)
+    version_last_request: Mutex<HashMap<ComponentId, Instant>>,
+    // The generation of each version running here, for the versions the worker reports one for
+    component_generations: Mutex<HashMap<ComponentId, u64>>,
+}
+
+// What we learned the last time we polled this worker, so one failed poll doesn't wipe it out of routing
+#[derive(Debug, Default)]
+struct WorkerRefreshState {
+    last_attempt: Option<Instant>,
+    last_success: Option<Instant>,
+    last_error: Option<String>,
+    consecutive_failures: u64,
+    last_known_components: Vec<ComponentId>,
+    load: Option<WorkerLoad>,
+}
+
+// Resource usage as reported by the worker (each is a fraction, where 1.0 means that resource is saturated)
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct WorkerLoad {
+    pub cpu: f64,
+    pub memory: f64,
+    pub network: f64,
+}
+
+impl WorkerLoad {
+    // A worker is only as free as its busiest resource
+    pub fn busiest(&self) -> f64 {
+        self.cpu.max(self.memory).max(self.network).clamp(0.0, 1.0)
+    }
+}
+
+// How much the latest observation moves our running average of a component's latency
+const OBSERVED_LATENCY_SMOOTHING: f64 = 0.3;
+
+// Which of the worker's reported latency percentiles we balance on, a degraded replica shows up in its tail first
+// (the average can look fine while one request in ten is stuck)
+const REPORTED_LATENCY_PERCENTILE: usize = 90;
+
+// How quickly one component responds on this worker, as the worker reports it and as we've seen it ourselves
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ComponentLatency {
+    pub reported_ms: Option<f64>,
+    pub observed_ms: Option<f64>,
+}
+
+impl ComponentLatency {
+    // `observed_weight` is how much to trust our own measurements over the worker's, `None` if we know nothing yet
+    pub fn estimate_ms(&self, observed_weight: f64) -> Option<f64> {
+        match (self.reported_ms, self.observed_ms) {
+            (Some(reported_ms), Some(observed_ms)) => {
+                Some(observed_ms * observed_weight + reported_ms * (1.0 - observed_weight))
+            }
+            (reported_ms, observed_ms) => reported_ms.or(observed_ms),
+        }
+    }
+}
+
+// Fed by both status polls and forwarded requests, an unhealthy worker is ejected from selection until it recovers
+#[derive(Debug)]
+struct HealthState {
+    healthy: bool,
+    consecutive_failures: u64,
+    consecutive_successes: u64,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+        }
+    }
+}
+
+// Set through the admin API, a worker that isn't schedulable keeps being polled but gets no new requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheduling {
+    Schedulable,
+    Unschedulable,
+    // Unschedulable, and waiting for in-flight requests to finish (so it can be taken down)
+    Draining,
+}
+
+// Everything the admin API reports about a worker
+// Note: Times are given as seconds ago, since an `Instant` means nothing outside this process
+#[derive(Debug, Serialize)]
+pub struct WorkerStatus {
+    url: String,
+    scheduling: Scheduling,
+    healthy: bool,
+    circuit_breaker: CircuitState,
+    in_flight_requests: usize,
+    drained: bool,
+    known_components: usize,
+    consecutive_failures: u64,
+    last_refresh_secs_ago: Option<f64>,
+    last_success_secs_ago: Option<f64>,
+    last_error: Option<String>,
+    load: Option<WorkerLoad>,
+}
+
+// Where a failed request points to the problem being, which decides the circuit breakers it counts against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureScope {
+    // The worker couldn't be reached at all
+    Worker,
+    // A 5xx (or anything else the worker got far enough to send back), which could just be that one component
+    Component,
+    // A timeout, which could be the component hanging or the whole worker (if it's the worker, the timeouts will keep
+    // coming from every component on it, with no successes in between to reset its breaker)
+    WorkerAndComponent,
+}
+
+// A worker's circuit breakers as the admin API reports them, component breakers keyed by "user/repo"
+#[derive(Debug, Serialize)]
+pub struct WorkerCircuitBreakers {
+    worker: String,
+    circuit_breaker: CircuitBreakerStatus,
+    components: BTreeMap<String, CircuitBreakerStatus>,
+}
+
+// Counts a request against a worker (and the component on that worker) for as long as it's alive
+#[derive(Debug)]
+pub struct InFlightRequest {
+    worker: Arc<WorkerNode>,
+    component_in_flight: Arc<AtomicUsize>,
+    version_in_flight: Arc<AtomicUsize>,
+}
+
+// The trials `try_acquire_circuit` took from half open breakers, given back when this is dropped
+// Note: By then the request's result has been recorded (which settles the breakers it was about) if it got one, so this
+// only frees up trials that would otherwise be lost, e.g. for a request whose body turned out to be too big
+#[derive(Debug)]
+pub struct CircuitPermit {
+    // `None` if the breakers are turned off, so there's nothing to give back
+    worker: Option<Arc<WorkerNode>>,
+    path: ComponentPath,
+    component_acquired: bool,
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        if let Some(worker) = &self.worker {
+            worker.circuit_breaker.release();
+            if self.component_acquired {
+                if let Some(circuit_breaker) = worker.component_breakers.lock().get(&self.path) {
+                    circuit_breaker.release();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.worker.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.component_in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.version_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl WorkerNode {
-    pub fn new(url: String) -> Self {
-        let client = reqwest::Client::builder()
-            .timeout(WORKER_TIMEOUT)
-            .build()
-            .unwrap();
+    // The client is shared between all workers (and the request forwarder), so connections get pooled
+    pub fn new(
+        url: String,
+        client: Client,
+        status_timeout: Duration,
+        health_config: HealthConfig,
+        circuit_breaker_config: CircuitBreakerConfig,
+    ) -> Self {
+        metrics::WORKER_HEALTHY.with_label_values(&[&url]).set(1.0);
+        let circuit_breaker = CircuitBreaker::new(&circuit_breaker_config, &url, None);
 
-        Self { client, url }
+        Self {
+            client,
+            url,
+            status_timeout,
+            refresh_state: Mutex::new(WorkerRefreshState::default()),
+            health_config,
+            health: Mutex::new(HealthState::default()),
+            circuit_breaker_config,
+            circuit_breaker,
+            component_breakers: Mutex::new(HashMap::new()),
+            scheduling: Mutex::new(Scheduling::Schedulable),
+            in_flight: AtomicUsize::new(0),
+            component_in_flight: Mutex::new(HashMap::new()),
+            version_in_flight: Mutex::new(HashMap::new()),
+            component_latencies: Mutex::new(HashMap::new()),
+            version_last_request: Mutex::new(HashMap::new()),
+            component_generations: Mutex::new(HashMap::new()),
+        }
     }
 
-    pub fn get_component_list(&self) -> Result<Vec<ComponentPath>, RouterError> {
+    pub fn get_status(&self) -> impl Future<Item = StatusResponse, Error = RouterError> {
         let url = format!("{}/meta/status", self.url);
 
-        let body = self.client.get(&url).send()?.text()?;
+        self.client
+            .get(&url)
+            .send()
+            .and_then(|mut resp| resp.text())
+            .map_err(RouterError::from)
+            .and_then(|body| {
+                let response: StatusResponse = serde_json::from_str(&body)?;
+                debug!(
+                    "Active components from worker response: {:?}",
+                    response.active_components
+                );
 
-        let response: StatusResponse = serde_json::from_str(&body)?;
-        debug!(
-            "Active components from worker response: {:?}",
-            response.active_components
-        );
-        let mut component_list = Vec::new();
+                Ok(response)
+            })
+            .timeout(self.status_timeout)
+            .map_err(RouterError::from)
+    }
 
-        for component in &response.active_components {
-            debug!("Adding new component name: {:?}", component.id.path);
-            component_list.push(component.id.path.clone());
-        }
+    // Asks the worker to start running a component, it answers once the component is up (or has failed to start)
+    pub fn activate(
+        &self,
+        request: &ActivateRequest,
+    ) -> impl Future<Item = ActivateResponse, Error = RouterError> {
+        let url = format!("{}/meta/activate", self.url);
+
+        self.client
+            .post(&url)
+            .json(request)
+            .send()
+            .and_then(|mut resp| resp.text())
+            .map_err(RouterError::from)
+            .and_then(|body| {
+                let response: ActivateResponse = serde_json::from_str(&body)?;
+                debug!("Activation response from worker: {:?}", response);
+
+                Ok(response)
+            })
+    }
+
+    // Asks the worker to stop running a component
+    pub fn deactivate(
+        &self,
+        request: &DeactivateRequest,
+    ) -> impl Future<Item = DeactivateResponse, Error = RouterError> {
+        let url = format!("{}/meta/deactivate", self.url);
+
+        self.client
+            .post(&url)
+            .json(request)
+            .send()
+            .and_then(|mut resp| resp.text())
+            .map_err(RouterError::from)
+            .and_then(|body| {
+                let response: DeactivateResponse = serde_json::from_str(&body)?;
+                debug!("Deactivation response from worker: {:?}", response);
+
+                Ok(response)
+            })
+    }
+
+    // Polls the worker, falling back to the last known component list if the poll fails
+    // (that fallback only lasts for `grace_period` after the last successful poll, then the worker is evicted)
+    pub fn refresh_component_list(
+        self: Arc<Self>,
+        grace_period: Duration,
+    ) -> impl Future<Item = Vec<ComponentId>, Error = ()> {
+        let poll_started = Instant::now();
+
+        self.get_status().then(move |poll_result| {
+            metrics::WORKER_REFRESH_DURATION
+                .with_label_values(&[&self.url])
+                .observe(poll_started.elapsed().as_secs_f64());
+
+            let mut refresh_state = self.refresh_state.lock();
+            refresh_state.last_attempt = Some(Instant::now());
+
+            let component_list = match poll_result {
+                Ok(status) => {
+                    let component_list: Vec<ComponentId> = status
+                        .active_components
+                        .iter()
+                        .map(|component| component.id.clone())
+                        .collect();
+                    debug!("Final component list from {}: {:?}", self.url, component_list);
+                    self.update_reported_latencies(&status);
+                    self.prune_component_in_flight();
+                    self.track_running_components(&component_list);
+                    self.prune_component_breakers(&component_list);
+                    *self.component_generations.lock() = status
+                        .active_components
+                        .iter()
+                        .filter_map(|component| Some((component.id.clone(), component.generation?)))
+                        .collect();
+
+                    if refresh_state.consecutive_failures > 0 {
+                        info!(
+                            "Worker {} recovered after {} failed status polls",
+                            self.url, refresh_state.consecutive_failures
+                        );
+                    }
+
+                    refresh_state.last_success = refresh_state.last_attempt;
+                    refresh_state.last_error = None;
+                    refresh_state.consecutive_failures = 0;
+                    refresh_state.last_known_components.clone_from(&component_list);
+                    refresh_state.load = Some(WorkerLoad {
+                        cpu: status.cpu_usage,
+                        memory: status.memory_usage,
+                        network: status.network_usage,
+                    });
+                    self.record_success();
 
-        debug!(
-            "Final component list from get_component_list: {:?}",
-            component_list
-        );
+                    component_list
+                }
+                Err(e) => {
+                    metrics::WORKER_REFRESH_FAILURES
+                        .with_label_values(&[&self.url])
+                        .inc();
+                    refresh_state.consecutive_failures += 1;
+                    refresh_state.last_error = Some(e.to_string());
+                    self.record_failure(&e);
 
-        Ok(component_list)
+                    let within_grace_period = refresh_state
+                        .last_success
+                        .is_some_and(|last_success| last_success.elapsed() < grace_period);
+
+                    if within_grace_period {
+                        warn!(
+                            "Status poll of worker {} failed ({} in a row), keeping {} last known components: {}",
+                            self.url,
+                            refresh_state.consecutive_failures,
+                            refresh_state.last_known_components.len(),
+                            e
+                        );
+                        refresh_state.last_known_components.clone()
+                    } else {
+                        warn!(
+                            "Status poll of worker {} failed ({} in a row), evicting it from the component map: {}",
+                            self.url, refresh_state.consecutive_failures, e
+                        );
+                        refresh_state.last_known_components.clear();
+                        refresh_state.load = None;
+                        self.component_generations.lock().clear();
+                        Vec::new()
+                    }
+                }
+            };
+
+            metrics::WORKER_COMPONENTS
+                .with_label_values(&[&self.url])
+                .set(component_list.len() as f64);
+
+            Ok(component_list)
+        })
     }
 
     pub fn request_url(&self) -> &str {
         &self.url
     }
+
+    pub fn is_schedulable(&self) -> bool {
+        *self.scheduling.lock() == Scheduling::Schedulable
+    }
+
+    pub fn load(&self) -> Option<WorkerLoad> {
+        self.refresh_state.lock().load
+    }
+
+    // Only components the worker is running keep their latencies
+    // Note: A replica that went a whole stat window without hits is forgotten, so it looks fast and gets tried again
+    // (otherwise a replica that was slow once would never get the traffic to show it has recovered)
+    fn update_reported_latencies(&self, status: &StatusResponse) {
+        let mut component_latencies = self.component_latencies.lock();
+        let old_latencies = mem::take(&mut *component_latencies);
+
+        for component in &status.active_components {
+            let path = &component.id.path;
+            let latency = if component.component_stats.hits > 0.0 {
+                ComponentLatency {
+                    reported_ms: Some(reported_latency_ms(&component.component_stats)),
+                    ..old_latencies.get(path).copied().unwrap_or_default()
+                }
+            } else {
+                ComponentLatency::default()
+            };
+            component_latencies.insert(path.clone(), latency);
+        }
+    }
+
+    // Counters nobody is holding are just for requests that have finished, so they can go
+    fn prune_component_in_flight(&self) {
+        self.component_in_flight
+            .lock()
+            .retain(|_, in_flight| Arc::strong_count(in_flight) > 1);
+        self.version_in_flight
+            .lock()
+            .retain(|_, in_flight| Arc::strong_count(in_flight) > 1);
+    }
+
+    // Versions that stopped running here are forgotten, new ones start out as if they'd just had a request
+    pub fn track_running_components(&self, component_list: &[ComponentId]) {
+        let now = Instant::now();
+        let mut version_last_request = self.version_last_request.lock();
+        version_last_request.retain(|id, _| component_list.contains(id));
+        for id in component_list {
+            version_last_request.entry(id.clone()).or_insert(now);
+        }
+    }
+
+    // Breakers for components that stopped running here go, so a redeployed component starts out closed
+    fn prune_component_breakers(&self, component_list: &[ComponentId]) {
+        self.component_breakers.lock().retain(|path, circuit_breaker| {
+            let running = component_list.iter().any(|id| id.path == *path);
+            if !running {
+                circuit_breaker.forget_metrics();
+            }
+            running
+        });
+    }
+
+    // When we last sent the version a request on this worker, `None` if we don't know it's running here
+    pub fn version_last_request(&self, id: &ComponentId) -> Option<Instant> {
+        self.version_last_request.lock().get(id).copied()
+    }
+
+    pub fn component_generation(&self, id: &ComponentId) -> Option<u64> {
+        self.component_generations.lock().get(id).copied()
+    }
+
+    pub fn record_latency(&self, path: &ComponentPath, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let mut component_latencies = self.component_latencies.lock();
+        let component_latency = component_latencies.entry(path.clone()).or_default();
+
+        component_latency.observed_ms = Some(match component_latency.observed_ms {
+            Some(observed_ms) => observed_ms + OBSERVED_LATENCY_SMOOTHING * (latency_ms - observed_ms),
+            None => latency_ms,
+        });
+    }
+
+    pub fn component_latency(&self, path: &ComponentPath) -> ComponentLatency {
+        self.component_latencies
+            .lock()
+            .get(path)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.health.lock().healthy
+    }
+
+    // Whether new requests can be sent here
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && self.is_schedulable()
+    }
+
+    pub fn record_success(&self) {
+        let mut health = self.health.lock();
+        health.consecutive_failures = 0;
+        health.consecutive_successes += 1;
+
+        if !health.healthy && health.consecutive_successes >= self.health_config.healthy_threshold {
+            info!(
+                "Worker {} succeeded {} times in a row, re-admitting it",
+                self.url, health.consecutive_successes
+            );
+            health.healthy = true;
+            metrics::WORKER_HEALTHY.with_label_values(&[&self.url]).set(1.0);
+        }
+    }
+
+    pub fn record_failure(&self, reason: &dyn Display) {
+        let mut health = self.health.lock();
+        health.consecutive_successes = 0;
+        health.consecutive_failures += 1;
+
+        if health.healthy && health.consecutive_failures >= self.health_config.unhealthy_threshold {
+            warn!(
+                "Worker {} failed {} times in a row, ejecting it: {}",
+                self.url, health.consecutive_failures, reason
+            );
+            health.healthy = false;
+            metrics::WORKER_HEALTHY.with_label_values(&[&self.url]).set(0.0);
+            metrics::WORKER_EJECTIONS.with_label_values(&[&self.url]).inc();
+        }
+    }
+
+    // Whether the worker's circuit breaker, and the component's breaker on this worker, let a request through (using up
+    // a trial on each that's half open, so only call this for the worker the request is actually going to, and hold
+    // onto the permit until the request's result is recorded)
+    pub fn try_acquire_circuit(self: &Arc<Self>, path: &ComponentPath) -> Option<CircuitPermit> {
+        if !self.circuit_breaker_config.enabled {
+            return Some(CircuitPermit {
+                worker: None,
+                path: path.clone(),
+                component_acquired: false,
+            });
+        }
+        if !self.circuit_breaker.try_acquire() {
+            return None;
+        }
+
+        let component_breakers = self.component_breakers.lock();
+        let component_breaker = component_breakers.get(path);
+        if !component_breaker.is_none_or(CircuitBreaker::try_acquire) {
+            self.circuit_breaker.release();
+            return None;
+        }
+        Some(CircuitPermit {
+            worker: Some(self.clone()),
+            path: path.clone(),
+            component_acquired: component_breaker.is_some(),
+        })
+    }
+
+    // A forwarded request got a response that isn't a 5xx, which counts towards health and closes half open breakers
+    pub fn record_request_success(&self, path: &ComponentPath) {
+        self.record_success();
+        if !self.circuit_breaker_config.enabled {
+            return;
+        }
+
+        self.circuit_breaker.record_success();
+        if let Some(circuit_breaker) = self.component_breakers.lock().get(path) {
+            circuit_breaker.record_success();
+        }
+    }
+
+    // Note: A failure scoped to the component (e.g. one app answering 5xx) doesn't count against the worker's health
+    // either, or one broken component would get the worker ejected for every component on it
+    pub fn record_request_failure(
+        &self,
+        path: &ComponentPath,
+        reason: &dyn Display,
+        scope: FailureScope,
+    ) {
+        let worker_wide = scope != FailureScope::Component;
+        if worker_wide {
+            self.record_failure(reason);
+        }
+        if !self.circuit_breaker_config.enabled {
+            return;
+        }
+
+        if worker_wide {
+            self.circuit_breaker.record_failure(reason);
+        }
+        if scope != FailureScope::Worker {
+            self.component_breakers
+                .lock()
+                .entry(path.clone())
+                .or_insert_with(|| {
+                    CircuitBreaker::new(&self.circuit_breaker_config, &self.url, Some(path))
+                })
+                .record_failure(reason);
+        }
+    }
+
+    pub fn circuit_breakers(&self) -> WorkerCircuitBreakers {
+        WorkerCircuitBreakers {
+            worker: self.url.clone(),
+            circuit_breaker: self.circuit_breaker.status(),
+            components: self
+                .component_breakers
+                .lock()
+                .iter()
+                .map(|(path, circuit_breaker)| (path.to_string(), circuit_breaker.status()))
+                .collect(),
+        }
+    }
+
+    // Drops the series that would otherwise keep reporting a removed worker's breakers
+    pub fn forget_circuit_breaker_metrics(&self) {
+        self.circuit_breaker.forget_metrics();
+        for circuit_breaker in self.component_breakers.lock().values() {
+            circuit_breaker.forget_metrics();
+        }
+    }
+
+    pub fn set_scheduling(&self, scheduling: Scheduling) {
+        let mut current_scheduling = self.scheduling.lock();
+        if *current_scheduling != scheduling {
+            info!(
+                "Worker {} is now {:?} (was {:?})",
+                self.url, scheduling, *current_scheduling
+            );
+            *current_scheduling = scheduling;
+        }
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn component_in_flight_requests(&self, path: &ComponentPath) -> usize {
+        self.component_in_flight
+            .lock()
+            .get(path)
+            .map_or(0, |in_flight| in_flight.load(Ordering::SeqCst))
+    }
+
+    pub fn version_in_flight_requests(&self, id: &ComponentId) -> usize {
+        self.version_in_flight
+            .lock()
+            .get(id)
+            .map_or(0, |in_flight| in_flight.load(Ordering::SeqCst))
+    }
+
+    // Hold onto the returned guard until the request (including streaming its response) is done
+    pub fn start_request(self: &Arc<Self>, id: &ComponentId) -> InFlightRequest {
+        let component_in_flight = self
+            .component_in_flight
+            .lock()
+            .entry(id.path.clone())
+            .or_default()
+            .clone();
+        let version_in_flight = self
+            .version_in_flight
+            .lock()
+            .entry(id.clone())
+            .or_default()
+            .clone();
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        component_in_flight.fetch_add(1, Ordering::SeqCst);
+        version_in_flight.fetch_add(1, Ordering::SeqCst);
+        self.version_last_request
+            .lock()
+            .insert(id.clone(), Instant::now());
+        InFlightRequest {
+            worker: self.clone(),
+            component_in_flight,
+            version_in_flight,
+        }
+    }
+
+    pub fn status(&self) -> WorkerStatus {
+        let scheduling = *self.scheduling.lock();
+        let healthy = self.is_healthy();
+        let in_flight_requests = self.in_flight_requests();
+        let refresh_state = self.refresh_state.lock();
+        let secs_ago = |instant: Option<Instant>| instant.map(|instant| instant.elapsed().as_secs_f64());
+
+        WorkerStatus {
+            url: self.url.clone(),
+            scheduling,
+            healthy,
+            circuit_breaker: self.circuit_breaker.state(),
+            in_flight_requests,
+            drained: scheduling == Scheduling::Draining && in_flight_requests == 0,
+            known_components: refresh_state.last_known_components.len(),
+            consecutive_failures: refresh_state.consecutive_failures,
+            last_refresh_secs_ago: secs_ago(refresh_state.last_attempt),
+            last_success_secs_ago: secs_ago(refresh_state.last_success),
+            last_error: refresh_state.last_error.clone(),
+            load: refresh_state.load,
+        }
+    }
+}
+
+// Workers report their latency percentiles evenly spaced from the fastest request (first) to the slowest (last), so
+// the tail percentile is read off between the two nearest of them, the average is only used if there are none
+fn reported_latency_ms(stats: &ComponentStats) -> f64 {
+    let percentiles = &stats.ms_latency_percentiles;
+    if percentiles.is_empty() {
+        return stats.avg_ms_latency;
+    }
+
+    // Kept in hundredths of a step between neighbouring percentiles, so the index never goes through a float
+    let position = REPORTED_LATENCY_PERCENTILE * (percentiles.len() - 1);
+    let lower = position / 100;
+    let upper = (lower + 1).min(percentiles.len() - 1);
+    let fraction = (position % 100) as f64 / 100.0;
+    percentiles[lower] + (percentiles[upper] - percentiles[lower]) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouterConfig;
+
+    fn worker() -> WorkerNode {
+        let config = RouterConfig::default();
+        WorkerNode::new(
+            "http://v9_w1.example.com".to_string(),
+            Client::new(),
+            config.timeouts.worker_status(),
+            config.health,
+            config.circuit_breaker,
+        )
+    }
+
+    fn path(repo: &str) -> ComponentPath {
+        ComponentPath {
+            user: "alice".to_string(),
+            repo: repo.to_string(),
+        }
+    }
+
+    #[test]
+    fn component_failures_leave_the_worker_available() {
+        let worker = worker();
+        let threshold = RouterConfig::default().health.unhealthy_threshold;
+
+        for _ in 0..threshold * 2 {
+            worker.record_request_failure(
+                &path("broken"),
+                &"responded with 500",
+                FailureScope::Component,
+            );
+        }
+
+        assert!(worker.is_available());
+    }
+
+    #[test]
+    fn worker_failures_eject_the_worker() {
+        let worker = worker();
+        let threshold = RouterConfig::default().health.unhealthy_threshold;
+
+        for _ in 0..threshold {
+            worker.record_request_failure(&path("any"), &"timed out", FailureScope::WorkerAndComponent);
+        }
+
+        assert!(!worker.is_available());
+    }
 }
@@ -0,0 +1,164 @@
+// A half open breaker hands out a limited number of trials, so a trial that never reaches the worker (here because the
+// router cut the client's body off for being too big) has to be given back, or the breaker is stuck refusing requests
+
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::rt::{Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server, StatusCode};
+
+const FAILURE_THRESHOLD: u64 = 2;
+const OPEN_TIME: Duration = Duration::from_secs(1);
+const MAX_REQUEST_BODY_BYTES: usize = 1024;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+const STATUS_RESPONSE: &str = r#"{
+    "cpu_usage": 0.1,
+    "memory_usage": 0.1,
+    "network_usage": 0.0,
+    "active_components": [{
+        "id": {"user": "alice", "repo": "app", "hash": "h1"},
+        "stat_window_seconds": 60.0,
+        "hits": 0.0,
+        "avg_response_bytes": 0.0,
+        "avg_ms_latency": 0.0,
+        "ms_latency_percentiles": []
+    }]
+}"#;
+
+// Kills the router (and removes its config file) when the test is done with it, even if the test failed
+struct Router {
+    child: Child,
+    config_file: PathBuf,
+}
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.config_file);
+    }
+}
+
+// Runs `alice/app`, which answers `/fail` with a 500 and anything else with a 200 (reading the whole body first)
+fn start_worker() -> u16 {
+    let (port_sender, port_receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn(|req: Request<Body>| {
+                let status = if req.uri().path().ends_with("/fail") {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                };
+                let is_status_poll = req.uri().path() == "/meta/status";
+
+                req.into_body().concat2().map(move |_| {
+                    let body = if is_status_poll { STATUS_RESPONSE } else { "done" };
+                    let mut response = Response::new(Body::from(body));
+                    *response.status_mut() = status;
+                    response
+                })
+            })
+        });
+        port_sender.send(server.local_addr().port()).unwrap();
+        hyper::rt::run(server.map_err(|e| panic!("Mock worker failed: {}", e)));
+    });
+
+    port_receiver.recv().unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_router(worker_port: u16) -> (Router, u16, u16) {
+    let (port, admin_port) = (free_port(), free_port());
+    let config_file = env::temp_dir().join(format!("v9_router_circuit_breaker_{}.toml", process::id()));
+    fs::write(
+        &config_file,
+        format!(
+            "[circuit_breaker]\nenabled = true\nfailure_threshold = {}\nopen_secs = {}\nhalf_open_max_requests = 1\n",
+            FAILURE_THRESHOLD,
+            OPEN_TIME.as_secs()
+        ),
+    )
+    .unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_v9_router"))
+        .env("V9_CONFIG", &config_file)
+        .env("V9_WORKERS", format!("http://127.0.0.1:{}", worker_port))
+        .env("V9_BIND_ADDRESS", "127.0.0.1")
+        .env("V9_PORT", port.to_string())
+        .env("V9_ADMIN_PORT", admin_port.to_string())
+        .env("V9_MAX_REQUEST_BODY_BYTES", MAX_REQUEST_BODY_BYTES.to_string())
+        .env("V9_LOG_SPEC", "warn")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    (Router { child, config_file }, port, admin_port)
+}
+
+// Once the router has polled the worker, `alice/app` shows up in the admin API
+fn wait_until_routing(admin_port: u16) {
+    let started = Instant::now();
+    let url = format!("http://127.0.0.1:{}/admin/components", admin_port);
+
+    while started.elapsed() < STARTUP_TIMEOUT {
+        let routing = reqwest::get(&url)
+            .and_then(|mut response| response.text())
+            .is_ok_and(|components| components.contains("alice"));
+        if routing {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    panic!("The router never started routing to the mock worker");
+}
+
+#[test]
+fn a_trial_cut_off_for_its_body_size_is_given_back() {
+    let worker_port = start_worker();
+    let (_router, port, admin_port) = start_router(worker_port);
+    wait_until_routing(admin_port);
+
+    let client = reqwest::Client::new();
+    let url = |method: &str| format!("http://127.0.0.1:{}/sl/alice/app/{}", port, method);
+
+    // Open the component's breaker, then wait for it to go half open
+    for _ in 0..FAILURE_THRESHOLD {
+        let response = client.get(&url("fail")).send().unwrap();
+        assert_eq!(response.status().as_u16(), 500);
+    }
+    assert_eq!(client.get(&url("run")).send().unwrap().status().as_u16(), 503);
+    thread::sleep(OPEN_TIME + Duration::from_millis(100));
+
+    // The only trial goes to a request whose body (streamed, so only caught on the way to the worker) is too big
+    // Note: Sent on a client of its own, the router stops reading the body, so the connection can't be used again
+    let too_big = reqwest::Body::new(Cursor::new(vec![0_u8; MAX_REQUEST_BODY_BYTES * 64]));
+    let response = reqwest::Client::new()
+        .post(&url("run"))
+        .body(too_big)
+        .send()
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 413);
+
+    // So the next request is let through as a trial, instead of the breaker waiting out another `open_secs`
+    assert_eq!(client.get(&url("run")).send().unwrap().status().as_u16(), 200);
+}
@@ -0,0 +1,142 @@
+// Load test for the forwarding path: requests to a slow worker have to wait on that worker in parallel, not queue up
+// behind each other (which is what happened when forwarding blocked the executor)
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use hyper::rt::Future;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server};
+use tokio::timer::Delay;
+
+// How long the mock worker takes to answer a component request
+const WORKER_DELAY: Duration = Duration::from_secs(1);
+const CONCURRENT_REQUESTS: usize = 16;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+const STATUS_RESPONSE: &str = r#"{
+    "cpu_usage": 0.1,
+    "memory_usage": 0.1,
+    "network_usage": 0.0,
+    "active_components": [{
+        "id": {"user": "alice", "repo": "slow", "hash": "h1"},
+        "stat_window_seconds": 60.0,
+        "hits": 0.0,
+        "avg_response_bytes": 0.0,
+        "avg_ms_latency": 0.0,
+        "ms_latency_percentiles": []
+    }]
+}"#;
+
+// Kills the router when the test is done with it, even if the test failed
+struct Router(Child);
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Runs `alice/slow`, which takes `WORKER_DELAY` to answer anything (without holding up its own executor)
+fn start_slow_worker() -> u16 {
+    let (port_sender, port_receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(|| {
+            service_fn(|req: Request<Body>| {
+                if req.uri().path() == "/meta/status" {
+                    Either::A(future::ok::<_, hyper::Error>(Response::new(Body::from(
+                        STATUS_RESPONSE,
+                    ))))
+                } else {
+                    Either::B(
+                        Delay::new(Instant::now() + WORKER_DELAY)
+                            .then(|_| Ok(Response::new(Body::from("done")))),
+                    )
+                }
+            })
+        });
+        port_sender.send(server.local_addr().port()).unwrap();
+        hyper::rt::run(server.map_err(|e| panic!("Mock worker failed: {}", e)));
+    });
+
+    port_receiver.recv().unwrap()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_router(worker_port: u16) -> (Router, u16, u16) {
+    let (port, admin_port) = (free_port(), free_port());
+
+    let child = Command::new(env!("CARGO_BIN_EXE_v9_router"))
+        .env_remove("V9_CONFIG")
+        .env("V9_WORKERS", format!("http://127.0.0.1:{}", worker_port))
+        .env("V9_BIND_ADDRESS", "127.0.0.1")
+        .env("V9_PORT", port.to_string())
+        .env("V9_ADMIN_PORT", admin_port.to_string())
+        .env("V9_LOG_SPEC", "warn")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    (Router(child), port, admin_port)
+}
+
+// Once the router has polled the worker, `alice/slow` shows up in the admin API
+fn wait_until_routing(admin_port: u16) {
+    let started = Instant::now();
+    let url = format!("http://127.0.0.1:{}/admin/components", admin_port);
+
+    while started.elapsed() < STARTUP_TIMEOUT {
+        let routing = reqwest::get(&url)
+            .and_then(|mut response| response.text())
+            .is_ok_and(|components| components.contains("alice"));
+        if routing {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    panic!("The router never started routing to the mock worker");
+}
+
+#[test]
+fn slow_worker_requests_are_served_in_parallel() {
+    let worker_port = start_slow_worker();
+    let (_router, port, admin_port) = start_router(worker_port);
+    wait_until_routing(admin_port);
+
+    let url = format!("http://127.0.0.1:{}/sl/alice/slow/run", port);
+    let started = Instant::now();
+    let requests: Vec<_> = (0..CONCURRENT_REQUESTS)
+        .map(|_| {
+            let url = url.clone();
+            thread::spawn(move || reqwest::get(&url).map(|response| response.status().as_u16()))
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.join().unwrap().unwrap(), 200);
+    }
+    let elapsed = started.elapsed();
+
+    // One at a time they'd take `CONCURRENT_REQUESTS` times the delay, in parallel about the delay once
+    assert!(
+        elapsed < WORKER_DELAY * 3,
+        "{} requests to a worker taking {:?} each took {:?} in all",
+        CONCURRENT_REQUESTS,
+        WORKER_DELAY,
+        elapsed
+    );
+}